@@ -9,4 +9,33 @@ pub enum Error {
     #[snafu(display("Failed to write config: {}", source))]
     WriteConfigFailed { source: std::io::Error },
 
+    #[snafu(display("Failed to read the token store: {}", source))]
+    ReadTokens { source: std::io::Error },
+
+    #[snafu(display("Failed to parse the token store: {}", source))]
+    ParseTokens { source: toml::de::Error },
+
+    #[snafu(display("Failed to write the token store: {}", source))]
+    WriteTokens { source: std::io::Error },
+
+    #[snafu(display("Failed to read the search index: {}", source))]
+    ReadIndex { source: std::io::Error },
+
+    #[snafu(display("Failed to parse the search index: {}", source))]
+    ParseIndex { source: serde_json::Error },
+
+    #[snafu(display("Failed to write the search index: {}", source))]
+    WriteIndex { source: std::io::Error },
+
+    #[snafu(display("Failed to read the cache snapshot: {}", source))]
+    ReadSnapshot { source: std::io::Error },
+
+    #[snafu(display("Failed to parse the cache snapshot: {}", source))]
+    ParseSnapshot { source: serde_json::Error },
+
+    #[snafu(display("Failed to write the cache snapshot: {}", source))]
+    WriteSnapshot { source: std::io::Error },
+
+    #[snafu(display("Failed to read the content cache: {}", source))]
+    ReadContentCache { source: std::io::Error },
 }
@@ -1,20 +1,28 @@
-use std::sync::OnceLock;
+use std::{collections::HashMap, sync::OnceLock};
 
 use arc_swap::ArcSwap;
-use onedrive::Onedrive;
+use onedrive::{Onedrive, TokenStatus};
 
 use tracing::{info, warn};
 
-use crate::{utils::config::handle_error, web::web_server};
+use crate::{
+    utils::{config::handle_error, password::hash_password, token_store::TokenStore},
+    web::web_server,
+};
 
 mod error;
 mod model;
 mod onedrive;
+mod storage;
 mod utils;
 mod web;
 mod worker;
 
-static DRIVE: OnceLock<ArcSwap<Onedrive>> = OnceLock::new();
+/// The OneDrive drives, keyed by their mount name
+static DRIVES: OnceLock<HashMap<String, ArcSwap<Onedrive>>> = OnceLock::new();
+
+/// Where the rotated refresh tokens of `DRIVES` are persisted
+static TOKEN_STORE: OnceLock<TokenStore> = OnceLock::new();
 
 // For replacing the name of the frontend
 static NAME: OnceLock<String> = OnceLock::new();
@@ -24,8 +32,22 @@ async fn main() {
     tracing_subscriber::fmt::init();
     info!("Starting the program");
 
+    // `onelist login [mount]` logs in interactively and exits
+    let mut args = std::env::args().skip(1);
+    let login = match args.next().as_deref() {
+        Some("login") => Some(args.next()),
+        Some("hash-password") => {
+            let password = args
+                .next()
+                .expect("Usage: onelist hash-password <password>");
+            println!("{}", hash_password(&password));
+            return;
+        }
+        _ => None,
+    };
+
     info!("Loading the configuration");
-    let mut config = match utils::config::Setting::load() {
+    let config = match utils::config::Setting::load() {
         Ok(config) => config,
         Err(e) => {
             warn!("Failed to load the configuration: {:?}", e);
@@ -36,59 +58,64 @@ async fn main() {
     info!("Configuration loaded: {:?}", config);
     NAME.set(config.setting.name.clone()).unwrap();
 
-    let onedrive = Onedrive::new(&config).await;
-    DRIVE.set(ArcSwap::from_pointee(onedrive)).unwrap();
-    let _ = config.save().await;
-    worker::worker();
-
-    web_server(config.clone()).await;
+    let token_store = TOKEN_STORE.get_or_init(|| TokenStore::new(&config.setting.token_store));
+    let tokens = token_store.load().await.unwrap_or_else(|e| {
+        warn!("Failed to load the token store: {:?}", e);
+        Default::default()
+    });
+
+    // A later drive would silently replace an earlier one of the same name
+    storage::check_mounts(&config.drives());
+
+    let mut drives = HashMap::new();
+    for drive in config
+        .drives()
+        .into_iter()
+        .filter(|drive| drive.local.is_none())
+    {
+        let mut auth = drive
+            .auth
+            .clone()
+            .unwrap_or_else(|| panic!("Drive '{}' needs either [auth] or [local]", drive.mount));
+        // The store has the latest rotated token, the config only the first one
+        if let Some(refresh_token) = tokens.get(&drive.mount) {
+            auth.refresh_token = Some(refresh_token.clone());
+        }
+        info!("Logging in to drive '{}'", drive.mount);
+        let mut onedrive = Onedrive::new(&auth).await;
+
+        let reauth_required = matches!(onedrive.status, TokenStatus::ReauthRequired { .. });
+        match &login {
+            Some(Some(mount)) if *mount == drive.mount => onedrive = login_cli(&onedrive).await,
+            Some(None) if reauth_required => onedrive = login_cli(&onedrive).await,
+            None if reauth_required => warn!(
+                "Drive '{}' needs to login, run `onelist login {}`",
+                drive.mount, drive.mount
+            ),
+            _ => {}
+        }
 
-    info!("Saving the configuration");
-    match config.save().await {
-        Ok(_) => info!("Configuration saved"),
-        Err(e) => warn!("Failed to save the configuration: {:?}", e),
+        drives.insert(drive.mount, ArcSwap::from_pointee(onedrive));
+    }
+    DRIVES.set(drives).unwrap();
+    worker::persist_tokens().await;
+    if login.is_some() {
+        info!("Login finished");
+        return;
     }
+    worker::worker();
+
+    web_server(config).await;
 }
 
-#[cfg(test)]
-mod tests {
-
-    use onedrive_api::{option::ObjectOption, resource::DriveItemField, ItemId, ItemLocation};
-
-    use super::*;
-
-    #[tokio::test]
-    async fn test_setting() {
-        tracing_subscriber::fmt::init();
-        info!("Starting the program");
-
-        info!("Loading the configuration");
-        let config = match utils::config::Setting::load() {
-            Ok(config) => config,
-            Err(e) => {
-                warn!("Failed to load the configuration: {:?}", e);
-                handle_error(e).await;
-                panic!("Failed to load the configuration")
-            }
-        };
-        info!("Configuration loaded: {:?}", config);
-        NAME.set(config.setting.name.clone()).unwrap();
-
-        let onedrive = Onedrive::new(&config).await;
-        DRIVE.set(ArcSwap::from_pointee(onedrive)).unwrap();
-
-        worker::worker();
-
-        let drive = DRIVE.get().unwrap();
-        let a = drive
-            .load()
-            .drive
-            .get_item_with_option(
-                ItemLocation::from_id(&ItemId("01YYY5XCXGP3XZEHOFNBGJOC4EU6FMCGIQ".to_string())),
-                ObjectOption::default().expand(DriveItemField::thumbnails, None),
-            )
-            .await
-            .unwrap();
-        dbg!(a);
-    }
+async fn login_cli(onedrive: &Onedrive) -> Onedrive {
+    let (prompt, signed_in) = onedrive
+        .start_login()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to start the login: {:?}", e));
+    println!("{}", prompt);
+
+    signed_in
+        .await
+        .unwrap_or_else(|e| panic!("Failed to login: {:?}", e))
 }
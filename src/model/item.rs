@@ -1,15 +1,22 @@
-use std::sync::Arc;
-
 use onedrive_api::resource::DriveItem;
 use snafu::Snafu;
 
-use super::{date_time_to_timestamp, thumb, Caches, FileInfo, FileTypes};
+use super::{cache_key, date_time_to_timestamp, thumb, Caches, FileInfo, FileTypes};
 
-pub fn parse_item(item: &DriveItem, caches: &Caches, home_path: &str) -> Result<FileInfo, Error> {
+/// Build a `FileInfo` for `item` of the drive mounted as `mount`, caching what
+/// came along with it
+pub fn parse_item(
+    item: &DriveItem,
+    caches: &Caches,
+    mount: &str,
+    home_path: &str,
+) -> Result<FileInfo, Error> {
     let id = item
         .id
         .as_ref()
-        .ok_or(Error::MissingId { item: item.clone() })?
+        .ok_or(Error::MissingId {
+            item: Box::new(item.clone()),
+        })?
         .0
         .to_string();
     let name = item.name.to_owned().unwrap_or_default();
@@ -17,19 +24,8 @@ pub fn parse_item(item: &DriveItem, caches: &Caches, home_path: &str) -> Result<
     let last_modified_date_time = date_time_to_timestamp(item.last_modified_date_time.to_owned());
     let download_url = item.download_url.to_owned().unwrap_or_default();
 
-    let path = item
-        .parent_reference
-        .as_ref()
-        .and_then(|parent| parent.get("path"))
-        .and_then(|path| path.as_str())
-        .unwrap_or_default()
-        .to_owned()
-        .replace("/drive/root:", "");
-    let path = if path.starts_with(home_path) {
-        path.replace(home_path, "")
-    } else {
-        path
-    };
+    let path = parent_path(item);
+    let path = strip_home(path, home_path).to_owned();
     let full_path = format!("{}/{}", path, name);
 
     let mime = item
@@ -38,26 +34,20 @@ pub fn parse_item(item: &DriveItem, caches: &Caches, home_path: &str) -> Result<
         .and_then(|file| file.get("mimeType"))
         .and_then(|mime| mime.as_str())
         .unwrap_or_default();
-    let file_type = if mime.starts_with("video") || name.ends_with(".mkv") {
-        FileTypes::Video
-    } else if mime.starts_with("audio") {
-        FileTypes::Audio
-    } else if download_url.is_empty() {
-        FileTypes::Folder
-    } else {
-        FileTypes::File
-    };
+    let file_type = FileTypes::detect(mime, &name, download_url.is_empty());
 
     // Cache the thumbnail if it exists
     if let Some(thumb) = &item.thumbnails {
         if let Ok(thumb) = thumb::parse_thumb(thumb) {
-            caches.thumb_cache.insert(id.clone(), Arc::new(thumb));
+            caches
+                .thumb_cache
+                .insert(cache_key(mount, &id), thumb.into());
         }
     }
 
     // Cache the download URL if it exists
     if !download_url.is_empty() {
-        caches.download_url_cache.insert(id.clone(), download_url);
+        caches.insert_download_url(cache_key(mount, &id), download_url);
     }
 
     let file_info = FileInfo {
@@ -67,16 +57,87 @@ pub fn parse_item(item: &DriveItem, caches: &Caches, home_path: &str) -> Result<
         last_modified_date_time,
         full_path,
         file_type,
+        etag: item.e_tag.as_ref().map(|tag| tag.0.clone()),
     };
 
-    caches.file_cache.insert(id, Arc::new(file_info.clone()));
+    // Its `full_path` is relative to the `home_dir` of this mount only
+    caches
+        .file_cache
+        .insert(cache_key(mount, &id), file_info.clone().into());
 
     Ok(file_info)
 }
 
+/// The path of the folder containing `item`, from the root of the drive
+pub fn parent_path(item: &DriveItem) -> &str {
+    let path = item
+        .parent_reference
+        .as_ref()
+        .and_then(|parent| parent.get("path"))
+        .and_then(|path| path.as_str())
+        .unwrap_or_default();
+    // `/drive/root:` for the user's drive, `/drives/{id}/root:` for libraries
+    path.split_once("root:").map_or(path, |(_, path)| path)
+}
+
+/// `path` relative to `home_path`, which OneDrive matches case-insensitively
+/// and only up to a `/`, left as is when outside of it
+fn strip_home<'a>(path: &'a str, home_path: &str) -> &'a str {
+    let len = home_path.len();
+    if !path.is_char_boundary(len) || path[..len].to_lowercase() != home_path.to_lowercase() {
+        return path;
+    }
+
+    match &path[len..] {
+        rest if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 pub enum Error {
     #[snafu(display("Missing ID"))]
-    MissingId { item: DriveItem },
+    MissingId { item: Box<DriveItem> },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_item() {
+        let item: DriveItem = serde_json::from_value(serde_json::json!({
+            "id": "a",
+            "name": "a.mkv",
+            "parentReference": { "path": "/drive/root:/Media" },
+            "@microsoft.graph.downloadUrl": "https://example.com/a",
+            "thumbnails": [{
+                "small": { "url": "s" },
+                "medium": { "url": "m" },
+                "large": { "url": "l" },
+            }],
+        }))
+        .unwrap();
+        let caches = Caches::new(Duration::from_secs(60));
+
+        let file = parse_item(&item, &caches, "movies", "/Media").unwrap();
+        assert_eq!(file.full_path, "/a.mkv");
+        // Cached under the id the web layer asks for
+        assert!(caches.download_url("movies:a").is_some());
+        assert!(caches.download_url("a").is_none());
+        assert!(caches.thumb_cache.get(&"movies:a".to_string()).is_some());
+        assert!(caches.file_cache.get(&"movies:a".to_string()).is_some());
+    }
+
+    #[test]
+    fn test_strip_home() {
+        assert_eq!(strip_home("/Media/Movies", "/Media"), "/Movies");
+        assert_eq!(strip_home("/media", "/Media"), "");
+        assert_eq!(strip_home("/MediaBackup/x", "/Media"), "/MediaBackup/x");
+        assert_eq!(strip_home("/Me", "/Media"), "/Me");
+        assert_eq!(strip_home("/a", ""), "/a");
+    }
 }
@@ -0,0 +1,49 @@
+use std::{fs::Metadata, time::UNIX_EPOCH};
+
+use super::{cache_key, Caches, FileInfo, FileTypes};
+
+/// Build a `FileInfo` for a file on the local disk, `full_path` being relative
+/// to the published root of the drive mounted as `mount`
+pub fn parse_metadata(
+    id: String,
+    full_path: String,
+    metadata: &Metadata,
+    caches: &Caches,
+    mount: &str,
+) -> FileInfo {
+    let name = full_path.rsplit('/').next().unwrap_or_default().to_string();
+    let size = if metadata.is_dir() {
+        0
+    } else {
+        metadata.len() as i64
+    };
+    let last_modified_date_time = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+
+    let mime = if metadata.is_dir() {
+        Default::default()
+    } else {
+        mime_guess::from_path(&name).first_raw().unwrap_or_default()
+    };
+    let file_type = FileTypes::detect(mime, &name, metadata.is_dir());
+
+    let file_info = FileInfo {
+        id: id.clone(),
+        name,
+        size,
+        last_modified_date_time,
+        full_path,
+        file_type,
+        etag: None,
+    };
+
+    caches
+        .file_cache
+        .insert(cache_key(mount, &id), file_info.clone().into());
+
+    file_info
+}
@@ -1,21 +1,207 @@
 pub mod item;
+pub mod local;
+pub mod snapshot;
 pub mod thumb;
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use mini_moka::sync::Cache;
 use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::utils::config::{CacheSetting, CacheSettings};
+
+/// Entries kept in each cache unless configured, items for `list_cache`
+const DOWNLOAD_URL_CAPACITY: u64 = 10_000;
+const LIST_CAPACITY: u64 = 200_000;
+const THUMB_CAPACITY: u64 = 10_000;
+const FILE_CAPACITY: u64 = 100_000;
+const PUBLISHED_CAPACITY: u64 = 100_000;
+/// Download URLs are dropped this long before they expire, so a redirect
+/// has time to be followed
+const DOWNLOAD_URL_MARGIN: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Caches {
     /// Cache for download URLs
-    pub download_url_cache: Cache<String, String>,
+    pub download_url_cache: Cache<String, DownloadUrl>,
     /// Cache for folder contents
-    pub list_cache: Cache<String, Arc<Vec<FileInfo>>>,
+    pub list_cache: Cache<String, Cached<Vec<FileInfo>>>,
     /// Cache for thumbnails
-    pub thumb_cache: Cache<String, Arc<Thumbnails>>,
+    pub thumb_cache: Cache<String, Cached<Thumbnails>>,
     /// Cache for file info
-    pub file_cache: Cache<String, Arc<FileInfo>>,
+    pub file_cache: Cache<String, Cached<FileInfo>>,
+    /// Cache for whether an item id lies under the published `home_dir`
+    pub published_cache: Cache<String, bool>,
+    /// How long to keep the download URLs that don't carry their expiry
+    download_url_ttl: Duration,
+    /// Where the changes of each watched drive were last read up to, by
+    /// mount, so listings restored from a snapshot can catch up
+    pub delta_urls: Mutex<HashMap<String, String>>,
+}
+
+/// The cache key of the item `id` of the drive mounted as `mount`, which is
+/// also the id the web layer knows it by
+pub fn cache_key(mount: &str, id: &str) -> String {
+    if mount.is_empty() {
+        id.to_string()
+    } else {
+        format!("{}:{}", mount, id)
+    }
+}
+
+/// A cached value along with when it was inserted, in seconds since the
+/// epoch, which the cache itself doesn't tell
+#[derive(Debug)]
+pub struct Cached<T> {
+    value: Arc<T>,
+    inserted: u64,
+}
+
+impl<T> Cached<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        Self::inserted_at(value, now())
+    }
+
+    /// `value` as if it had been inserted at `inserted`
+    pub(crate) fn inserted_at(value: Arc<T>, inserted: u64) -> Self {
+        Self { value, inserted }
+    }
+
+    pub fn value(&self) -> &Arc<T> {
+        &self.value
+    }
+
+    pub fn inserted(&self) -> u64 {
+        self.inserted
+    }
+}
+
+impl<T> Clone for Cached<T> {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            inserted: self.inserted,
+        }
+    }
+}
+
+impl<T> Deref for Cached<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> From<T> for Cached<T> {
+    fn from(value: T) -> Self {
+        Self::new(Arc::new(value))
+    }
+}
+
+/// A download URL along with when it stops working
+#[derive(Debug, Clone)]
+pub struct DownloadUrl {
+    url: String,
+    expires: Instant,
+}
+
+impl Caches {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_setting(&CacheSettings::default(), ttl, ttl)
+    }
+
+    /// Build the caches from `setting`, the TTLs left unset being `ttl`, or
+    /// `listing_ttl` for folder contents and file info, which may be longer
+    /// when something else invalidates them as they change
+    pub fn with_setting(setting: &CacheSettings, ttl: Duration, listing_ttl: Duration) -> Self {
+        let list_capacity = setting.list.capacity.unwrap_or(LIST_CAPACITY);
+        Self {
+            download_url_cache: build(&setting.download_url, ttl, DOWNLOAD_URL_CAPACITY),
+            list_cache: Cache::builder()
+                .time_to_live(setting.list.ttl.map_or(listing_ttl, Duration::from_secs))
+                .max_capacity(list_capacity)
+                .weigher(|_, children: &Cached<Vec<FileInfo>>| {
+                    u32::try_from(children.len() + 1).unwrap_or(u32::MAX)
+                })
+                .build(),
+            thumb_cache: build(&setting.thumb, ttl, THUMB_CAPACITY),
+            file_cache: build(&setting.file, listing_ttl, FILE_CAPACITY),
+            published_cache: build(&setting.published, ttl, PUBLISHED_CAPACITY),
+            download_url_ttl: setting.download_url.ttl.map_or(ttl, Duration::from_secs),
+            delta_urls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cache the download URL of `id` for the configured TTL, or until it
+    /// expires if that comes first
+    pub fn insert_download_url(&self, id: String, url: String) {
+        let mut ttl = self.download_url_ttl;
+        if let Some(left) = url_expiry(&url).map(|left| left.saturating_sub(DOWNLOAD_URL_MARGIN)) {
+            if left < ttl {
+                debug!(
+                    "Caching the download URL of '{}' for {:?} instead of {:?}, as it expires",
+                    id, left, ttl
+                );
+                ttl = left;
+            }
+        }
+
+        let expires = Instant::now() + ttl;
+        self.download_url_cache
+            .insert(id, DownloadUrl { url, expires });
+    }
+
+    /// The cached download URL of `id`, unless it has expired
+    pub fn download_url(&self, id: &str) -> Option<String> {
+        let key = id.to_string();
+        let cached = self.download_url_cache.get(&key)?;
+        if cached.expires <= Instant::now() {
+            self.download_url_cache.invalidate(&key);
+            return None;
+        }
+
+        Some(cached.url)
+    }
+}
+
+fn build<V>(setting: &CacheSetting, ttl: Duration, capacity: u64) -> Cache<String, V>
+where
+    V: Clone + Send + Sync + 'static,
+{
+    Cache::builder()
+        .time_to_live(setting.ttl.map_or(ttl, Duration::from_secs))
+        .max_capacity(setting.capacity.unwrap_or(capacity))
+        .build()
+}
+
+/// How long `url` has left, read from the `tempauth` token of SharePoint
+/// and OneDrive for Business URLs; personal OneDrive URLs don't tell
+fn url_expiry(url: &str) -> Option<Duration> {
+    let url = url::Url::parse(url).ok()?;
+    let (_, token) = url
+        .query_pairs()
+        .find(|(name, _)| name.eq_ignore_ascii_case("tempauth"))?;
+
+    // A JWT, possibly behind a version such as `v1.`
+    let expires = token.split('.').find_map(|part| {
+        let json = URL_SAFE_NO_PAD.decode(part).ok()?;
+        let claims: serde_json::Value = serde_json::from_slice(&json).ok()?;
+        claims.get("exp")?.as_u64()
+    })?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Some(Duration::from_secs(expires.saturating_sub(now)))
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -25,18 +211,21 @@ pub struct Thumbnails {
     pub large: String,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileInfo {
-    id: String,
-    name: String,
-    size: i64,
-    last_modified_date_time: i64,
-    full_path: String,
+    pub id: String,
+    pub name: String,
+    pub size: i64,
+    pub last_modified_date_time: i64,
+    pub full_path: String,
     #[serde(rename = "type")]
-    file_type: FileTypes,
+    pub file_type: FileTypes,
+    /// Changes whenever the item does, unknown for local files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum FileTypes {
     File,
     Folder,
@@ -44,6 +233,28 @@ pub enum FileTypes {
     Audio,
 }
 
+impl FileTypes {
+    fn detect(mime: &str, name: &str, is_folder: bool) -> Self {
+        if mime.starts_with("video") || name.ends_with(".mkv") {
+            FileTypes::Video
+        } else if mime.starts_with("audio") {
+            FileTypes::Audio
+        } else if is_folder {
+            FileTypes::Folder
+        } else {
+            FileTypes::File
+        }
+    }
+}
+
+/// Seconds since the epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 fn date_time_to_timestamp(date_time: Option<String>) -> i64 {
     date_time
         .and_then(|date_time| {
@@ -53,3 +264,79 @@ fn date_time_to_timestamp(date_time: Option<String>) -> i64 {
         })
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sharepoint_url(expires: u64) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"aud":"x","exp":{}}}"#, expires));
+        format!(
+            "https://contoso.sharepoint.com/_layouts/15/download.aspx?UniqueId=1&tempauth=v1.{}.{}.sig",
+            header, claims
+        )
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_url_expiry() {
+        let left = url_expiry(&sharepoint_url(now() + 3600)).unwrap();
+        assert!(left > Duration::from_secs(3500) && left <= Duration::from_secs(3600));
+        assert_eq!(
+            url_expiry(&sharepoint_url(now() - 10)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(url_expiry("https://public.dm.files.1drv.com/y4mabc"), None);
+    }
+
+    #[test]
+    fn test_download_url() {
+        let caches = Caches::new(Duration::from_secs(600));
+
+        caches.insert_download_url("a".to_string(), sharepoint_url(now() + 3600));
+        assert!(caches.download_url("a").is_some());
+
+        // About to expire, so not worth handing out
+        caches.insert_download_url("b".to_string(), sharepoint_url(now() + 30));
+        assert!(caches.download_url("b").is_none());
+
+        caches.insert_download_url("c".to_string(), "https://example.com/c".to_string());
+        assert_eq!(
+            caches.download_url("c").as_deref(),
+            Some("https://example.com/c")
+        );
+
+        // Kept for the configured TTL when the URL lasts longer
+        let expires = |id: &str| {
+            caches
+                .download_url_cache
+                .get(&id.to_string())
+                .unwrap()
+                .expires
+        };
+        assert!(expires("a") <= Instant::now() + Duration::from_secs(600));
+        let setting = CacheSettings {
+            download_url: CacheSetting {
+                ttl: Some(7200),
+                capacity: None,
+            },
+            ..Default::default()
+        };
+        let caches =
+            Caches::with_setting(&setting, Duration::from_secs(600), Duration::from_secs(600));
+        caches.insert_download_url("d".to_string(), sharepoint_url(now() + 3 * 3600));
+        let expires = caches
+            .download_url_cache
+            .get(&"d".to_string())
+            .unwrap()
+            .expires;
+        assert!(expires > Instant::now() + Duration::from_secs(7000));
+    }
+}
@@ -0,0 +1,359 @@
+//! Caches saved to disk, so a restart doesn't start them cold
+//!
+//! A snapshot only applies to the config it was taken with: listings are
+//! cached by published path and already filtered by the hide rules, so a
+//! change of drives, `home_dir` or hide rules makes it useless, or worse.
+//!
+//! Entries keep the TTL they had left when saved, told by when they were
+//! inserted. The caches only know one TTL for all their entries, so the
+//! snapshot drops the restored ones itself once their time is up.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use mini_moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::fs;
+use tracing::info;
+
+use crate::{
+    error::{Error, ParseSnapshotSnafu, ReadSnapshotSnafu, WriteSnapshotSnafu},
+    utils::{config::Setting, gzip, token_store::write_atomic},
+};
+
+use super::{now, Cached, Caches, FileInfo, Thumbnails};
+
+/// Bumped whenever the cached types change, discarding older snapshots
+const VERSION: u32 = 3;
+
+#[derive(Debug)]
+pub struct CacheSnapshot {
+    path: PathBuf,
+    fingerprint: String,
+}
+
+/// What is checked before reading the rest of a snapshot
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    fingerprint: String,
+    saved_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(flatten)]
+    header: Header,
+    /// Each entry along with the seconds it had left to live
+    list: Vec<(String, Vec<FileInfo>, u64)>,
+    file: Vec<(String, FileInfo, u64)>,
+    thumb: Vec<(String, Thumbnails, u64)>,
+    delta_urls: HashMap<String, String>,
+}
+
+impl CacheSnapshot {
+    pub fn new(path: impl Into<PathBuf>, config: &Setting) -> Self {
+        Self {
+            path: path.into(),
+            fingerprint: fingerprint(config),
+        }
+    }
+
+    /// Fill `caches` from the last snapshot, returning whether there was a
+    /// usable one
+    pub async fn load(&self, caches: &Caches) -> Result<bool, Error> {
+        let compressed = match fs::read(&self.path).await {
+            Ok(compressed) => compressed,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).context(ReadSnapshotSnafu),
+        };
+        let json = gzip::decompress(&compressed).context(ReadSnapshotSnafu)?;
+
+        let header: Header = serde_json::from_slice(&json).context(ParseSnapshotSnafu)?;
+        if header.version != VERSION {
+            info!(
+                "Discarding the cache snapshot of version {}, expected {}",
+                header.version, VERSION
+            );
+            return Ok(false);
+        }
+        if header.fingerprint != self.fingerprint {
+            info!("Discarding the cache snapshot taken with other drives or hide rules");
+            return Ok(false);
+        }
+
+        let snapshot: Snapshot = serde_json::from_slice(&json).context(ParseSnapshotSnafu)?;
+        let now = now();
+        let age = now.saturating_sub(snapshot.header.saved_at);
+        restore(&caches.list_cache, snapshot.list, age, now);
+        restore(&caches.file_cache, snapshot.file, age, now);
+        restore(&caches.thumb_cache, snapshot.thumb, age, now);
+        *caches.delta_urls.lock().unwrap() = snapshot.delta_urls;
+
+        Ok(true)
+    }
+
+    pub async fn save(&self, caches: &Caches) -> Result<(), Error> {
+        let now = now();
+        let snapshot = Snapshot {
+            header: Header {
+                version: VERSION,
+                fingerprint: self.fingerprint.clone(),
+                saved_at: now,
+            },
+            list: entries(&caches.list_cache, now),
+            file: entries(&caches.file_cache, now),
+            thumb: entries(&caches.thumb_cache, now),
+            delta_urls: caches.delta_urls.lock().unwrap().clone(),
+        };
+
+        let json = serde_json::to_vec(&snapshot).context(ParseSnapshotSnafu)?;
+        let compressed = gzip::compress(&json).context(WriteSnapshotSnafu)?;
+        write_atomic(&self.path, &compressed)
+            .await
+            .context(WriteSnapshotSnafu)
+    }
+}
+
+/// What the cached listings and the search index depend on
+pub(crate) fn fingerprint(config: &Setting) -> String {
+    let drives: Vec<_> = config
+        .drives()
+        .into_iter()
+        .map(|drive| {
+            let root = drive.local.map(|local| local.root);
+            let location = drive.auth.map(|auth| auth.location);
+            (drive.mount, drive.home_dir, root, location)
+        })
+        .collect();
+
+    serde_json::to_string(&(drives, &config.hide)).unwrap()
+}
+
+/// The seconds `cache` keeps its entries
+fn ttl<T>(cache: &Cache<String, Cached<T>>) -> u64
+where
+    T: Send + Sync + 'static,
+{
+    cache
+        .policy()
+        .time_to_live()
+        .map_or(u64::MAX, |ttl| ttl.as_secs())
+}
+
+/// The entries of `cache` with the seconds they have left
+fn entries<T>(cache: &Cache<String, Cached<T>>, now: u64) -> Vec<(String, T, u64)>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    let ttl = ttl(cache);
+    cache
+        .iter()
+        .filter_map(|entry| {
+            let left = ttl.saturating_sub(now.saturating_sub(entry.value().inserted()));
+            let value = T::clone(entry.value());
+            (left > 0).then(|| (entry.key().clone(), value, left))
+        })
+        .collect()
+}
+
+/// Put `entries` back in `cache` for what they had left `age` seconds ago,
+/// as if they had been inserted back then
+fn restore<T>(cache: &Cache<String, Cached<T>>, entries: Vec<(String, T, u64)>, age: u64, now: u64)
+where
+    T: Send + Sync + 'static,
+{
+    let ttl = ttl(cache);
+    let mut restored = Vec::new();
+    for (key, entry, left) in entries {
+        let left = left.saturating_sub(age).min(ttl);
+        if left == 0 {
+            continue;
+        }
+
+        let value = Arc::new(entry);
+        restored.push((key.clone(), Arc::downgrade(&value), left));
+        let at = now.saturating_sub(ttl - left);
+        cache.insert(key, Cached::inserted_at(value, at));
+    }
+
+    expire(cache.clone(), restored);
+}
+
+/// Drop the `restored` entries of `cache` once their time is up, unless they
+/// were replaced since, as the cache gave them its whole TTL
+fn expire<T>(cache: Cache<String, Cached<T>>, mut restored: Vec<(String, Weak<T>, u64)>)
+where
+    T: Send + Sync + 'static,
+{
+    if restored.is_empty() {
+        return;
+    }
+
+    restored.sort_by_key(|(_, _, left)| Reverse(*left));
+    let start = tokio::time::Instant::now();
+    tokio::spawn(async move {
+        while let Some((key, value, left)) = restored.pop() {
+            tokio::time::sleep_until(start + Duration::from_secs(left)).await;
+            let current = cache.get(&key);
+            if current.is_some_and(|current| Weak::ptr_eq(&Arc::downgrade(current.value()), &value))
+            {
+                cache.invalidate(&key);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::FileTypes;
+
+    use super::*;
+
+    fn config(home_dir: &str) -> Setting {
+        Setting::parse(&format!("home_dir = \"{}\"", home_dir))
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json.gz");
+
+        let caches = Caches::new(Duration::from_secs(60));
+        let file = FileInfo {
+            id: "a".to_string(),
+            name: "a.mkv".to_string(),
+            size: 1,
+            last_modified_date_time: 0,
+            full_path: "/a.mkv".to_string(),
+            file_type: FileTypes::Video,
+            etag: None,
+        };
+        caches
+            .list_cache
+            .insert("/".to_string(), vec![file.clone()].into());
+        caches.file_cache.insert("a".to_string(), file.into());
+        caches
+            .delta_urls
+            .lock()
+            .unwrap()
+            .insert(String::new(), "https://example.com/delta".to_string());
+        CacheSnapshot::new(&path, &config("/"))
+            .save(&caches)
+            .await
+            .unwrap();
+
+        let restored = Caches::new(Duration::from_secs(60));
+        let snapshot = CacheSnapshot::new(&path, &config("/"));
+        assert!(snapshot.load(&restored).await.unwrap());
+        assert_eq!(restored.list_cache.get(&"/".to_string()).unwrap().len(), 1);
+        assert_eq!(
+            restored.file_cache.get(&"a".to_string()).unwrap().name,
+            "a.mkv"
+        );
+        assert_eq!(restored.delta_urls.lock().unwrap().len(), 1);
+
+        // Another home_dir puts other items under the same paths
+        let other = Caches::new(Duration::from_secs(60));
+        let snapshot = CacheSnapshot::new(&path, &config("/Movies"));
+        assert!(!snapshot.load(&other).await.unwrap());
+        assert!(other.list_cache.get(&"/".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let drive = |location: &str| {
+            Setting::parse(&format!(
+                r#"
+                [[drives]]
+                mount = ""
+
+                [drives.auth]
+                client_id = "id"
+                client_secret = "secret"
+                type = "organizations"
+                location = {}
+                "#,
+                location
+            ))
+        };
+
+        // Another drive puts other items under the same paths
+        assert_eq!(
+            fingerprint(&drive(r#""me""#)),
+            fingerprint(&drive(r#""me""#))
+        );
+        assert_ne!(
+            fingerprint(&drive(r#""me""#)),
+            fingerprint(&drive(r#"{ site = "a" }"#))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ttl_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json.gz");
+        let snapshot = CacheSnapshot::new(&path, &config("/"));
+
+        let saved = serde_json::json!({
+            "version": VERSION,
+            "fingerprint": snapshot.fingerprint,
+            "saved_at": now(),
+            "list": [["/", [], 1], ["/a", [], 600]],
+            "file": [],
+            "thumb": [],
+            "delta_urls": {},
+        });
+        let compressed = gzip::compress(saved.to_string().as_bytes()).unwrap();
+        std::fs::write(&path, compressed).unwrap();
+
+        let caches = Caches::new(Duration::from_secs(60));
+        assert!(snapshot.load(&caches).await.unwrap());
+        assert!(caches.list_cache.get(&"/".to_string()).is_some());
+
+        // Saved again with what they have left, at most the cache's TTL
+        snapshot.save(&caches).await.unwrap();
+        let json = gzip::decompress(&std::fs::read(&path).unwrap()).unwrap();
+        let saved: Snapshot = serde_json::from_slice(&json).unwrap();
+        let left: HashMap<_, _> = saved
+            .list
+            .into_iter()
+            .map(|(key, _, left)| (key, left))
+            .collect();
+        assert!(left["/"] <= 1);
+        assert_eq!(left["/a"], 60);
+
+        // Dropped once the TTL they had left is up, not the cache's
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(caches.list_cache.get(&"/".to_string()).is_none());
+        assert!(caches.list_cache.get(&"/a".to_string()).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json.gz");
+        let snapshot = CacheSnapshot::new(&path, &config("/"));
+
+        let old = serde_json::json!({
+            "version": VERSION - 1,
+            "fingerprint": snapshot.fingerprint,
+            "saved_at": now(),
+            "list": [["/", [{ "id": "a" }]]],
+        });
+        let compressed = gzip::compress(old.to_string().as_bytes()).unwrap();
+        std::fs::write(&path, compressed).unwrap();
+
+        // Discarded before the entries it can't parse anymore are read
+        let caches = Caches::new(Duration::from_secs(60));
+        assert!(!snapshot.load(&caches).await.unwrap());
+        assert!(caches.list_cache.get(&"/".to_string()).is_none());
+    }
+}
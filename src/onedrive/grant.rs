@@ -0,0 +1,136 @@
+//! OAuth grants not covered by `onedrive_api::Auth`
+
+use std::time::{Duration, Instant};
+
+use onedrive_api::{Auth, Tenant};
+use serde::{de::DeserializeOwned, Deserialize};
+use snafu::ResultExt;
+use url::Url;
+
+use super::{Error, RequestSnafu, Token};
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceCode {
+    device_code: String,
+    pub message: String,
+    expires_in: u64,
+    interval: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+impl From<TokenResponse> for Token {
+    fn from(val: TokenResponse) -> Self {
+        Token {
+            access_token: val.access_token,
+            refresh_token: val.refresh_token,
+            expires_at: Instant::now() + Duration::from_secs(val.expires_in),
+        }
+    }
+}
+
+fn endpoint(tenant: &Tenant, endpoint: &str) -> Url {
+    let issuer = match tenant {
+        Tenant::Common => "common",
+        Tenant::Organizations => "organizations",
+        Tenant::Consumers => "consumers",
+        Tenant::Issuer(issuer) => issuer,
+    };
+
+    let mut url = Url::parse("https://login.microsoftonline.com").unwrap();
+    url.path_segments_mut()
+        .unwrap()
+        .extend([issuer, "oauth2", "v2.0", endpoint]);
+    url
+}
+
+async fn request<T: DeserializeOwned>(url: Url, params: &[(&str, &str)]) -> Result<T, Error> {
+    let response = reqwest::Client::new()
+        .post(url)
+        .form(params)
+        .send()
+        .await
+        .context(RequestSnafu)?;
+
+    if response.status().is_success() {
+        response.json().await.context(RequestSnafu)
+    } else {
+        let error: ErrorResponse = response.json().await.context(RequestSnafu)?;
+        Err(Error::Grant {
+            error: error.error,
+            description: error.error_description,
+        })
+    }
+}
+
+/// Start the device authorization grant, whose `message` tells the user
+/// which code to enter on any device with a browser
+pub async fn start_device_code(auth: &Auth, scope: &str) -> Result<DeviceCode, Error> {
+    request(
+        endpoint(auth.tenant(), "devicecode"),
+        &[("client_id", auth.client_id()), ("scope", scope)],
+    )
+    .await
+}
+
+/// Poll until the user has entered the device code and signed in
+pub async fn poll_device_code(auth: &Auth, device: DeviceCode) -> Result<Token, Error> {
+    let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval);
+    while Instant::now() < deadline {
+        tokio::time::sleep(interval).await;
+
+        let ret: Result<TokenResponse, _> = request(
+            endpoint(auth.tenant(), "token"),
+            &[
+                ("client_id", auth.client_id()),
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("device_code", &device.device_code),
+            ],
+        )
+        .await;
+
+        match ret {
+            Ok(token) => return Ok(token.into()),
+            Err(Error::Grant { error, .. }) if error == "authorization_pending" => {}
+            Err(Error::Grant { error, .. }) if error == "slow_down" => {
+                interval += Duration::from_secs(5);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(Error::Grant {
+        error: "expired_token".to_string(),
+        description: "The device code expired before the user signed in".to_string(),
+    })
+}
+
+/// Request an app-only token with the client credentials grant, which needs
+/// no user interaction and issues no refresh token
+pub async fn client_credentials(auth: &Auth, client_secret: &str) -> Result<Token, Error> {
+    let token: TokenResponse = request(
+        endpoint(auth.tenant(), "token"),
+        &[
+            ("client_id", auth.client_id()),
+            ("client_secret", client_secret),
+            ("grant_type", "client_credentials"),
+            ("scope", "https://graph.microsoft.com/.default"),
+        ],
+    )
+    .await?;
+
+    Ok(token.into())
+}
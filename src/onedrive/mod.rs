@@ -0,0 +1,343 @@
+//! OneDrive storage implementation
+//! How to get the client_id and client_secret:
+//! 1. Go to https://entra.microsoft.com/
+//! 2. Create a new app registration
+//! 3. Go to the app registration and create a new client secret
+//! 4. Use the client id and client secret as the credentials
+//! 5. Add the following permissions to the app registration:
+//!    - Files.ReadWrite
+//!    - offline_access
+//!    - User.Read
+
+mod grant;
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+use onedrive_api::{ClientCredential, TokenResponse};
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::utils::config::{Auth as AuthSetting, DriveLocation, LoginMethod};
+
+#[derive(Debug, Clone)]
+pub struct Onedrive {
+    pub auth: onedrive_api::Auth,
+    pub setting: AuthSetting,
+    pub token: Token,
+    pub status: TokenStatus,
+    pub location: onedrive_api::DriveLocation,
+    pub drive: onedrive_api::OneDrive,
+}
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Instant,
+}
+
+/// Where a drive is in its token lifecycle
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum TokenStatus {
+    /// The access token is valid and refreshed before it expires
+    Ok,
+    /// Refreshing failed for a reason that may go away, retried with backoff
+    Degraded { error: String, retries: u32 },
+    /// The credentials were rejected, someone has to log in again
+    ReauthRequired { error: String },
+}
+
+impl Onedrive {
+    /// Sign in with the configured credentials without ever prompting the
+    /// user, a drive that needs an interactive login starts out as
+    /// `ReauthRequired`
+    pub async fn new(config: &AuthSetting) -> Self {
+        let auth = onedrive_api::Auth::new(
+            config.client_id.clone(),
+            onedrive_api::Permission::new_read()
+                .offline_access(true)
+                .access_shared(config.location != DriveLocation::Me),
+            "http://localhost:8077/redirect",
+            config.r#type.0.clone(),
+        );
+
+        if config.login == LoginMethod::ClientCredentials && config.location == DriveLocation::Me {
+            panic!("client_credentials login needs a user, group, site or drive location");
+        }
+
+        let location = onedrive_api::DriveLocation::from(&config.location);
+        let onedrive = Self {
+            auth,
+            setting: config.clone(),
+            token: Token {
+                access_token: String::new(),
+                refresh_token: config.refresh_token.clone(),
+                expires_at: Instant::now(),
+            },
+            status: TokenStatus::Ok,
+            drive: onedrive_api::OneDrive::new("", location.clone()),
+            location,
+        };
+
+        match onedrive.refresh().await {
+            Ok(onedrive) => onedrive,
+            Err(e) => {
+                warn!("Failed to sign in: {}", e);
+                onedrive.with_error(&e)
+            }
+        }
+    }
+
+    fn with_token(self, token: Token) -> Self {
+        let drive = onedrive_api::OneDrive::new(&token.access_token, self.location.clone());
+        Self {
+            token,
+            status: TokenStatus::Ok,
+            drive,
+            ..self
+        }
+    }
+
+    /// Record a failed refresh, the current token is kept while it lasts
+    pub fn with_error(&self, e: &Error) -> Self {
+        let status = if e.is_transient() {
+            let retries = match &self.status {
+                TokenStatus::Degraded { retries, .. } => retries + 1,
+                _ => 0,
+            };
+            TokenStatus::Degraded {
+                error: e.to_string(),
+                retries,
+            }
+        } else {
+            TokenStatus::ReauthRequired {
+                error: e.to_string(),
+            }
+        };
+
+        Self {
+            status,
+            ..self.clone()
+        }
+    }
+
+    /// Start an interactive login, returning the instructions for the user
+    /// and a future resolving to the drive once they have signed in
+    pub async fn start_login(
+        &self,
+    ) -> Result<(String, BoxFuture<'static, Result<Self, Error>>), Error> {
+        let onedrive = self.clone();
+        match self.setting.login {
+            LoginMethod::Redirect => {
+                let url = self.auth.code_auth_url();
+                // temporary webserver to get the code
+                let listener = TcpListener::bind("0.0.0.0:8077")
+                    .await
+                    .context(BindFailedSnafu)?;
+
+                let prompt = format!("Open the following URL in your browser:\n{}", url);
+                Ok((
+                    prompt,
+                    Box::pin(async move {
+                        let token = Self::login_with_redirect(&onedrive, listener).await?;
+                        Ok(onedrive.with_token(token))
+                    }),
+                ))
+            }
+            LoginMethod::DeviceCode => {
+                let device = grant::start_device_code(&self.auth, &scope(&self.setting)).await?;
+
+                Ok((
+                    device.message.clone(),
+                    Box::pin(async move {
+                        let token = grant::poll_device_code(&onedrive.auth, device).await?;
+                        Ok(onedrive.with_token(token))
+                    }),
+                ))
+            }
+            LoginMethod::ClientCredentials => Ok((
+                "Requesting a new app-only token".to_string(),
+                Box::pin(async move { onedrive.refresh().await }),
+            )),
+        }
+    }
+
+    async fn login_with_redirect(&self, listener: TcpListener) -> Result<Token, Error> {
+        let code: String;
+        if let Ok((stream, _)) = listener.accept().await {
+            code = handle_connection(stream).await.context(BindFailedSnafu)?;
+        } else {
+            return Err(Error::GetRedirectUrl {
+                source: io::Error::other("Failed to get the redirect url"),
+            });
+        }
+
+        let code = parse_code(&code).ok_or(ParseCodeSnafu { s: code }.build())?;
+        // Get the token from the code
+        let token = self
+            .auth
+            .login_with_code(&code, &credential(&self.setting))
+            .await
+            .context(RefreshTokenSnafu)?
+            .into();
+
+        Ok(token)
+    }
+
+    /// Get a new access token with the stored credentials
+    pub async fn refresh(&self) -> Result<Self, Error> {
+        debug!("Refreshing the token");
+        let new_token = match &self.token.refresh_token {
+            _ if self.setting.login == LoginMethod::ClientCredentials => {
+                info!("app-only access, request a token with the client credentials");
+                grant::client_credentials(&self.auth, &self.setting.client_secret).await?
+            }
+            Some(refresh_token) => self
+                .auth
+                .login_with_refresh_token(refresh_token, &credential(&self.setting))
+                .await
+                .context(RefreshTokenSnafu)?
+                .into(),
+            None => return Err(Error::MissingRefreshToken),
+        };
+
+        Ok(self.clone().with_token(new_token))
+    }
+}
+
+impl From<&DriveLocation> for onedrive_api::DriveLocation {
+    fn from(val: &DriveLocation) -> Self {
+        match val {
+            DriveLocation::Me => Self::me(),
+            DriveLocation::User(id) => Self::from_user(id),
+            DriveLocation::Group(id) => Self::from_group(id),
+            DriveLocation::Site(id) => Self::from_site(id),
+            DriveLocation::Drive(id) => Self::from_id(onedrive_api::DriveId(id.clone())),
+        }
+    }
+}
+
+impl From<TokenResponse> for Token {
+    fn from(val: TokenResponse) -> Self {
+        Token {
+            access_token: val.access_token,
+            refresh_token: val.refresh_token,
+            expires_at: Instant::now() + Duration::from_secs(val.expires_in_secs),
+        }
+    }
+}
+
+/// Public clients, e.g. ones using the device code flow, have no secret
+fn credential(setting: &AuthSetting) -> ClientCredential {
+    if setting.client_secret.is_empty() {
+        ClientCredential::None
+    } else {
+        ClientCredential::Secret(setting.client_secret.clone())
+    }
+}
+
+/// The scope `onedrive_api::Permission` requests for the same drive
+fn scope(setting: &AuthSetting) -> String {
+    if setting.location == DriveLocation::Me {
+        "files.read offline_access".to_string()
+    } else {
+        "files.read.all offline_access".to_string()
+    }
+}
+
+fn parse_code(url: &str) -> Option<String> {
+    let url = Url::parse(format!("http://localhost:8077{}", url).as_str());
+    let url = match url {
+        Ok(url) => url,
+        Err(_) => return None,
+    };
+    // Rest of the code...
+    let pairs = url.query_pairs();
+
+    for (key, value) in pairs {
+        if key == "code" {
+            return Some(value.to_string());
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("Failed to refresh the token {}", source))]
+    RefreshToken { source: onedrive_api::Error },
+
+    #[snafu(display("Failed to parse the code {}", s))]
+    ParseCode { s: String },
+
+    #[snafu(display("Failed to login {}", source))]
+    GetRedirectUrl { source: io::Error },
+
+    #[snafu(display("Failed to bind the 10080 port {}", source))]
+    BindFailed { source: io::Error },
+
+    #[snafu(display("Failed to request the token {}", source))]
+    Request { source: reqwest::Error },
+
+    #[snafu(display("Token request rejected: {} {}", error, description))]
+    Grant { error: String, description: String },
+
+    #[snafu(display("No refresh token, please login"))]
+    MissingRefreshToken,
+}
+
+impl Error {
+    /// Whether retrying the same request later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::RefreshToken { source } => source.status_code().is_none_or(|status| {
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }),
+            Error::Request { .. } => true,
+            Error::Grant { error, .. } => {
+                matches!(error.as_str(), "server_error" | "temporarily_unavailable")
+            }
+            _ => false,
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) -> Result<String, std::io::Error> {
+    // 创建缓冲区
+    let mut buffer = [0; 1024];
+
+    // 异步读取数据
+    match stream.read(&mut buffer).await {
+        Ok(n) => {
+            let request = String::from_utf8_lossy(&buffer[..n]);
+
+            // 解析请求的第一行来获取 URI
+            let request_line = request.lines().next().unwrap_or("");
+            let uri = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+            // 构建响应
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nYou requested: {}",
+                uri
+            );
+
+            // 异步发送响应
+            stream.write_all(response.as_bytes()).await?;
+            stream.flush().await?;
+            Ok(uri.to_string())
+        }
+        Err(e) => Err(e),
+    }
+}
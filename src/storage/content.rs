@@ -0,0 +1,575 @@
+//! An on-disk cache of the content of proxied downloads
+//!
+//! Each file is kept at its own offsets in a sparse file, along with the
+//! byte ranges received so far, so the part of a video already watched by
+//! someone is served locally. Entries are keyed by item id and eTag, and the
+//! least recently used ones make room for new content, apart from the ones
+//! still being written.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind, SeekFrom},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::warn;
+
+use crate::error::{Error, ReadContentCacheSnafu};
+
+/// The most read from disk at once
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub struct ContentCache {
+    dir: PathBuf,
+    /// The most bytes kept on disk
+    capacity: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// The keys of the entries by when they were last used
+    recency: BTreeMap<u64, String>,
+    /// Bytes on disk across all entries
+    stored: u64,
+    /// Bumped on every use, ordering the entries by recency
+    clock: u64,
+    /// Bumped for every new entry, so writers of an entry since removed
+    /// can't record ranges in one created again under the same key
+    generation: u64,
+}
+
+/// What is known of a cached file, saved next to it
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    id: String,
+    size: u64,
+    /// The response headers served along with the content
+    headers: Vec<(String, String)>,
+    /// The ranges on disk, sorted and apart from each other
+    ranges: Vec<Range<u64>>,
+    #[serde(skip)]
+    used: u64,
+    #[serde(skip)]
+    generation: u64,
+    /// The writers still streaming into the file, which keep it from being
+    /// evicted
+    #[serde(skip)]
+    writers: usize,
+}
+
+/// The size and response headers of a cached file
+#[derive(Debug)]
+pub struct Stored {
+    pub size: u64,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Writes the content of a file as it's downloaded
+#[derive(Debug)]
+pub struct Writer {
+    cache: Arc<ContentCache>,
+    key: String,
+    /// The generation of the entry written to
+    generation: u64,
+    file: File,
+    offset: u64,
+}
+
+impl State {
+    /// Add `entry` as the most recently used one
+    fn insert(&mut self, key: String, mut entry: Entry) -> &mut Entry {
+        self.clock += 1;
+        self.generation += 1;
+        entry.used = self.clock;
+        entry.generation = self.generation;
+        self.stored += covered(&entry.ranges);
+        self.recency.insert(self.clock, key.clone());
+        self.entries.entry(key).insert_entry(entry).into_mut()
+    }
+
+    /// Mark `key` as just used, returning its entry
+    fn touch(&mut self, key: &str) -> Option<&mut Entry> {
+        let entry = self.entries.get_mut(key)?;
+        self.clock += 1;
+        self.recency.remove(&entry.used);
+        self.recency.insert(self.clock, key.to_string());
+        entry.used = self.clock;
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used);
+        self.stored -= covered(&entry.ranges);
+        Some(entry)
+    }
+}
+
+impl ContentCache {
+    pub fn new(dir: impl Into<PathBuf>, capacity: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            capacity,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The key of the content of `id` as of `etag`
+    pub fn key(id: &str, etag: &str) -> String {
+        let digest = Sha256::new()
+            .chain_update(id)
+            .chain_update([0])
+            .chain_update(etag)
+            .finalize();
+        URL_SAFE_NO_PAD.encode(digest)
+    }
+
+    /// Pick up the entries left by a previous run, removing the files that
+    /// don't belong to any
+    pub async fn load(&self) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)
+            .await
+            .context(ReadContentCacheSnafu)?;
+
+        let mut files = Vec::new();
+        let mut dir = fs::read_dir(&self.dir)
+            .await
+            .context(ReadContentCacheSnafu)?;
+        while let Some(file) = dir.next_entry().await.context(ReadContentCacheSnafu)? {
+            files.push(file.file_name().to_string_lossy().into_owned());
+        }
+
+        let mut loaded = Vec::new();
+        for name in &files {
+            let Some(key) = name.strip_suffix(".json") else {
+                continue;
+            };
+            if !files.iter().any(|file| file == key) {
+                continue;
+            }
+            let entry = fs::read(self.dir.join(name))
+                .await
+                .ok()
+                .and_then(|json| serde_json::from_slice::<Entry>(&json).ok());
+            if let Some(entry) = entry {
+                loaded.push((key.to_string(), entry));
+            }
+        }
+
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            for (key, entry) in loaded {
+                if !state.entries.contains_key(&key) {
+                    state.insert(key, entry);
+                }
+            }
+            self.evict(&mut state)
+        };
+        self.remove(evicted).await;
+
+        let stray = {
+            let state = self.state.lock().unwrap();
+            files
+                .into_iter()
+                .filter(|file| {
+                    let key = file.strip_suffix(".json").unwrap_or(file);
+                    !state.entries.contains_key(key)
+                })
+                .collect::<Vec<_>>()
+        };
+        for file in stray {
+            let _ = fs::remove_file(self.dir.join(file)).await;
+        }
+
+        Ok(())
+    }
+
+    /// The size and headers of `key`, when any of it is cached
+    pub fn lookup(&self, key: &str) -> Option<Stored> {
+        let state = self.state.lock().unwrap();
+        let entry = state.entries.get(key)?;
+        Some(Stored {
+            size: entry.size,
+            headers: entry.headers.clone(),
+        })
+    }
+
+    /// Read `range` of `key`, when all of it is on disk
+    pub async fn read(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Option<impl Stream<Item = io::Result<Vec<u8>>>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !contains(&state.entries.get(key)?.ranges, &range) {
+                return None;
+            }
+            state.touch(key);
+        }
+
+        let mut file = File::open(self.dir.join(key)).await.ok()?;
+        file.seek(SeekFrom::Start(range.start)).await.ok()?;
+
+        let left = range.end - range.start;
+        Some(stream::unfold(
+            (file, left),
+            |(mut file, left)| async move {
+                if left == 0 {
+                    return None;
+                }
+
+                let mut buf = vec![0; left.min(CHUNK_SIZE) as usize];
+                match file.read(&mut buf).await {
+                    Ok(0) => Some((Err(ErrorKind::UnexpectedEof.into()), (file, 0))),
+                    Ok(read) => {
+                        buf.truncate(read);
+                        Some((Ok(buf), (file, left - read as u64)))
+                    }
+                    Err(e) => Some((Err(e), (file, 0))),
+                }
+            },
+        ))
+    }
+
+    /// Start caching the content of `key` from `offset` on, unless the file
+    /// couldn't fit
+    pub async fn writer(
+        self: &Arc<Self>,
+        key: &str,
+        id: &str,
+        size: u64,
+        headers: Vec<(String, String)>,
+        offset: u64,
+    ) -> Option<Writer> {
+        if size > self.capacity || offset >= size {
+            return None;
+        }
+
+        // Older versions of the file won't be asked for anymore
+        let (outdated, generation) = {
+            let mut state = self.state.lock().unwrap();
+            let outdated: Vec<_> = state
+                .entries
+                .iter()
+                .filter(|(other, entry)| entry.id == id && *other != key)
+                .map(|(other, _)| other.clone())
+                .collect();
+            for other in &outdated {
+                state.remove(other);
+            }
+
+            let entry = match state.touch(key) {
+                Some(entry) => entry,
+                None => state.insert(
+                    key.to_string(),
+                    Entry {
+                        id: id.to_string(),
+                        size,
+                        headers,
+                        ranges: Vec::new(),
+                        used: 0,
+                        generation: 0,
+                        writers: 0,
+                    },
+                ),
+            };
+            entry.writers += 1;
+            (outdated, entry.generation)
+        };
+        self.remove(outdated).await;
+
+        let open = async {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(self.dir.join(key))
+                .await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            io::Result::Ok(file)
+        };
+        match open.await {
+            Ok(file) => Some(Writer {
+                cache: self.clone(),
+                key: key.to_string(),
+                generation,
+                file,
+                offset,
+            }),
+            Err(e) => {
+                warn!("Failed to cache the content of '{}': {}", id, e);
+                self.release(key, generation);
+                None
+            }
+        }
+    }
+
+    /// Mark `range` of `key` as on disk, making room for it, unless the
+    /// entry was written to as of another `generation`
+    async fn record(&self, key: &str, generation: u64, range: Range<u64>) {
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let Some(entry) = state.entries.get_mut(key) else {
+                return;
+            };
+            if entry.generation != generation {
+                return;
+            }
+
+            let before = covered(&entry.ranges);
+            insert(&mut entry.ranges, range);
+            state.stored += covered(&entry.ranges) - before;
+            state.touch(key);
+            self.evict(state)
+        };
+        self.remove(evicted).await;
+    }
+
+    /// Drop the least recently used entries until the rest fits, sparing
+    /// the ones being written
+    fn evict(&self, state: &mut State) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while state.stored > self.capacity {
+            let Some(key) = state
+                .recency
+                .values()
+                .find(|key| state.entries[*key].writers == 0)
+                .cloned()
+            else {
+                break;
+            };
+            state.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    /// Let the entry `key` be evicted once its writers are done
+    fn release(&self, key: &str, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(key) {
+            if entry.generation == generation {
+                entry.writers -= 1;
+            }
+        }
+    }
+
+    async fn remove(&self, keys: Vec<String>) {
+        for key in keys {
+            let _ = fs::remove_file(self.dir.join(&key)).await;
+            let _ = fs::remove_file(self.dir.join(format!("{}.json", key))).await;
+        }
+    }
+
+    /// Save what is known of `key` next to it, as of `generation`
+    async fn save(&self, key: &str, generation: u64) {
+        let json = {
+            let state = self.state.lock().unwrap();
+            match state.entries.get(key) {
+                Some(entry) if entry.generation == generation => serde_json::to_vec(entry).unwrap(),
+                _ => return,
+            }
+        };
+        if let Err(e) = fs::write(self.dir.join(format!("{}.json", key)), json).await {
+            warn!("Failed to save the content cache entry '{}': {}", key, e);
+        }
+    }
+}
+
+impl Writer {
+    /// Write the next part of the content
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        // Only recorded once readable, tokio finishing writes in the
+        // background otherwise
+        self.file.write_all(data).await?;
+        self.file.flush().await?;
+
+        let start = self.offset;
+        self.offset += data.len() as u64;
+        self.cache
+            .record(&self.key, self.generation, start..self.offset)
+            .await;
+        Ok(())
+    }
+
+    /// Save the ranges written, so they survive restarts
+    pub async fn finish(mut self) {
+        let _ = self.file.flush().await;
+        self.cache.save(&self.key, self.generation).await;
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.cache.release(&self.key, self.generation);
+    }
+}
+
+/// Add `range` to `ranges`, merging it with the ranges it touches
+fn insert(ranges: &mut Vec<Range<u64>>, mut range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+
+    ranges.retain(|other| {
+        if other.start > range.end || other.end < range.start {
+            return true;
+        }
+        range.start = range.start.min(other.start);
+        range.end = range.end.max(other.end);
+        false
+    });
+    let at = ranges.partition_point(|other| other.start < range.start);
+    ranges.insert(at, range);
+}
+
+/// Whether `ranges` cover all of `range`
+fn contains(ranges: &[Range<u64>], range: &Range<u64>) -> bool {
+    range.is_empty()
+        || ranges
+            .iter()
+            .any(|other| other.start <= range.start && range.end <= other.end)
+}
+
+fn covered(ranges: &[Range<u64>]) -> u64 {
+    ranges.iter().map(|range| range.end - range.start).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    async fn read(cache: &ContentCache, key: &str, range: Range<u64>) -> Option<Vec<u8>> {
+        let chunks: Vec<_> = cache.read(key, range).await?.try_collect().await.unwrap();
+        Some(chunks.concat())
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut ranges = Vec::new();
+        insert(&mut ranges, 10..20);
+        insert(&mut ranges, 0..5);
+        insert(&mut ranges, 5..8);
+        assert_eq!(ranges, vec![0..8, 10..20]);
+        insert(&mut ranges, 7..12);
+        assert_eq!(ranges, vec![0..20]);
+
+        assert!(contains(&ranges, &(3..20)));
+        assert!(!contains(&ranges, &(3..21)));
+        assert_eq!(covered(&[0..8, 10..20]), 18);
+    }
+
+    #[tokio::test]
+    async fn test_partial_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(ContentCache::new(dir.path(), 100));
+        cache.load().await.unwrap();
+        let key = ContentCache::key("a", "v1");
+
+        // Only the middle of the file was downloaded
+        let mut writer = cache.writer(&key, "a", 10, Vec::new(), 3).await.unwrap();
+        writer.write(b"3456").await.unwrap();
+        writer.finish().await;
+
+        assert_eq!(cache.lookup(&key).unwrap().size, 10);
+        assert_eq!(read(&cache, &key, 4..6).await.unwrap(), b"45");
+        assert!(read(&cache, &key, 0..6).await.is_none());
+
+        // Entries survive restarts
+        let reloaded = ContentCache::new(dir.path(), 100);
+        reloaded.load().await.unwrap();
+        assert_eq!(read(&reloaded, &key, 3..7).await.unwrap(), b"3456");
+
+        // A new version of the file replaces the old one
+        let updated = ContentCache::key("a", "v2");
+        cache
+            .writer(&updated, "a", 10, Vec::new(), 0)
+            .await
+            .unwrap();
+        assert!(cache.lookup(&key).is_none());
+        assert!(!dir.path().join(&key).exists());
+    }
+
+    #[tokio::test]
+    async fn test_evict() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(ContentCache::new(dir.path(), 10));
+        cache.load().await.unwrap();
+
+        for id in ["a", "b"] {
+            let key = ContentCache::key(id, "v1");
+            let mut writer = cache.writer(&key, id, 6, Vec::new(), 0).await.unwrap();
+            writer.write(b"012345").await.unwrap();
+            writer.finish().await;
+        }
+
+        // The least recently used file made room for the other
+        assert!(cache.lookup(&ContentCache::key("a", "v1")).is_none());
+        assert!(cache.lookup(&ContentCache::key("b", "v1")).is_some());
+        // Files larger than the whole cache aren't cached
+        let key = ContentCache::key("c", "v1");
+        assert!(cache.writer(&key, "c", 11, Vec::new(), 0).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evict_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(ContentCache::new(dir.path(), 10));
+        cache.load().await.unwrap();
+        let (a, b) = (ContentCache::key("a", "v1"), ContentCache::key("b", "v1"));
+
+        let mut writing = cache.writer(&a, "a", 6, Vec::new(), 0).await.unwrap();
+        writing.write(b"012345").await.unwrap();
+        let mut writer = cache.writer(&b, "b", 10, Vec::new(), 0).await.unwrap();
+        writer.write(b"0123").await.unwrap();
+
+        // Still being written, so kept even though it's the oldest
+        writer.write(b"45").await.unwrap();
+        assert!(cache.lookup(&a).is_some());
+
+        writing.finish().await;
+        writer.write(b"67").await.unwrap();
+        assert!(cache.lookup(&a).is_none());
+        assert_eq!(read(&cache, &b, 0..8).await.unwrap(), b"01234567");
+    }
+
+    #[tokio::test]
+    async fn test_stale_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(ContentCache::new(dir.path(), 100));
+        cache.load().await.unwrap();
+        let key = ContentCache::key("a", "v1");
+
+        let mut stale = cache.writer(&key, "a", 10, Vec::new(), 0).await.unwrap();
+        // Another version replaces the entry, then the first one comes back
+        let updated = ContentCache::key("a", "v2");
+        cache
+            .writer(&updated, "a", 10, Vec::new(), 0)
+            .await
+            .unwrap();
+        let _writer = cache.writer(&key, "a", 10, Vec::new(), 5).await.unwrap();
+
+        // What the old writer writes goes to the removed file, not the entry
+        stale.write(b"01234").await.unwrap();
+        stale.finish().await;
+        assert!(read(&cache, &key, 0..5).await.is_none());
+    }
+}
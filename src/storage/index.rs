@@ -0,0 +1,441 @@
+//! A local index of the names under the published root
+//!
+//! Graph search is slow and only eventually consistent, so a crawler keeps
+//! the listing of every folder here and searches are answered from memory.
+//! The index is saved as gzipped JSON so a restart doesn't start it over.
+//! After the first crawl, only the folders the delta workers report as
+//! changed are listed again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::fs;
+use tracing::info;
+
+use crate::{
+    error::{Error, ParseIndexSnafu, ReadIndexSnafu, WriteIndexSnafu},
+    model::{FileInfo, FileTypes},
+    utils::{gzip, natural::natural_cmp, token_store::write_atomic},
+};
+
+use super::SEARCH_LIMIT;
+
+/// Bumped whenever the saved format changes, discarding older indexes
+const VERSION: u32 = 1;
+
+/// The children of every indexed folder, by the folder's path
+type Folders = HashMap<String, Vec<FileInfo>>;
+
+#[derive(Debug)]
+pub struct SearchIndex {
+    path: PathBuf,
+    /// What the indexed paths depend on, see `model::snapshot::fingerprint`
+    fingerprint: String,
+    folders: RwLock<Folders>,
+    /// Whether the index was loaded or crawled, searches going to the
+    /// backend until then
+    ready: AtomicBool,
+    /// Folders changed since they were listed
+    stale: Mutex<HashSet<String>>,
+    /// Folders whose changes were missed, to crawl with all they hold
+    trees: Mutex<HashSet<String>>,
+}
+
+/// What is checked before reading the rest of a saved index
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// Missing from the indexes saved before it was versioned
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    fingerprint: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(flatten)]
+    header: Header,
+    folders: Folders,
+}
+
+impl SearchIndex {
+    pub fn new(path: impl Into<PathBuf>, fingerprint: String) -> Self {
+        Self {
+            path: path.into(),
+            fingerprint,
+            folders: RwLock::new(Folders::new()),
+            ready: AtomicBool::new(false),
+            stale: Mutex::new(HashSet::new()),
+            trees: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Load the index saved by a previous run, if any was saved with the
+    /// same version and config
+    pub async fn load(&self) -> Result<(), Error> {
+        let compressed = match fs::read(&self.path).await {
+            Ok(compressed) => compressed,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context(ReadIndexSnafu),
+        };
+        let json = gzip::decompress(&compressed).context(ReadIndexSnafu)?;
+
+        let header: Header = serde_json::from_slice(&json).context(ParseIndexSnafu)?;
+        if header.version != VERSION {
+            info!(
+                "Discarding the search index of version {}, expected {}",
+                header.version, VERSION
+            );
+            return Ok(());
+        }
+        if header.fingerprint != self.fingerprint {
+            info!("Discarding the search index built with other drives or hide rules");
+            return Ok(());
+        }
+
+        let snapshot: Snapshot = serde_json::from_slice(&json).context(ParseIndexSnafu)?;
+        *self.folders.write().unwrap() = snapshot.folders;
+        self.set_ready();
+        Ok(())
+    }
+
+    pub async fn save(&self) -> Result<(), Error> {
+        let json = {
+            let folders = self.folders.read().unwrap();
+            serde_json::to_vec(&Snapshot {
+                header: Header {
+                    version: VERSION,
+                    fingerprint: self.fingerprint.clone(),
+                },
+                folders: folders.clone(),
+            })
+            .context(ParseIndexSnafu)?
+        };
+
+        let compressed = gzip::compress(&json).context(WriteIndexSnafu)?;
+
+        write_atomic(&self.path, &compressed)
+            .await
+            .context(WriteIndexSnafu)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Replace the children of `folder`
+    pub fn update(&self, folder: &str, children: Vec<FileInfo>) {
+        self.folders
+            .write()
+            .unwrap()
+            .insert(folder.to_string(), children);
+    }
+
+    /// Drop the folders for which `keep` is false
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) {
+        self.folders
+            .write()
+            .unwrap()
+            .retain(|folder, _| keep(folder));
+    }
+
+    /// Drop `folder` and everything below it
+    pub fn remove(&self, folder: &str) {
+        self.retain(|indexed| !is_under(indexed, folder));
+    }
+
+    pub fn contains(&self, folder: &str) -> bool {
+        self.folders.read().unwrap().contains_key(folder)
+    }
+
+    /// The indexed subfolders of `folder`
+    pub fn subfolders(&self, folder: &str) -> Vec<String> {
+        self.folders
+            .read()
+            .unwrap()
+            .get(folder)
+            .into_iter()
+            .flatten()
+            .filter(|child| matches!(child.file_type, FileTypes::Folder))
+            .map(|child| child.full_path.clone())
+            .collect()
+    }
+
+    /// The folders holding any of the items `ids`
+    pub fn folders_holding(&self, ids: &HashSet<String>) -> Vec<String> {
+        self.folders
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, children)| children.iter().any(|child| ids.contains(&child.id)))
+            .map(|(folder, _)| folder.clone())
+            .collect()
+    }
+
+    /// Have `folder` listed again
+    pub fn invalidate(&self, folder: &str) {
+        self.stale.lock().unwrap().insert(folder.to_string());
+    }
+
+    /// Have `folder` crawled again with everything below it
+    pub fn invalidate_tree(&self, folder: &str) {
+        self.trees.lock().unwrap().insert(folder.to_string());
+    }
+
+    /// The folders to list again since the last call
+    pub fn take_stale(&self) -> Vec<String> {
+        self.stale.lock().unwrap().drain().collect()
+    }
+
+    /// The folders to crawl again since the last call
+    pub fn take_trees(&self) -> Vec<String> {
+        self.trees.lock().unwrap().drain().collect()
+    }
+
+    /// The items whose name matches every word of `query`, best matches
+    /// first
+    pub fn search(&self, query: &str) -> Vec<FileInfo> {
+        let query = query.to_lowercase();
+        let terms: Vec<_> = query.split_whitespace().collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let folders = self.folders.read().unwrap();
+        let mut results: Vec<_> = folders
+            .values()
+            .flatten()
+            .filter_map(|item| {
+                let name = item.name.to_lowercase();
+                terms
+                    .iter()
+                    .map(|term| score(&name, term))
+                    .try_fold(Score::Prefix, |worst, score| Some(worst.max(score?)))
+                    .map(|score| (score, item))
+            })
+            .collect();
+        results.sort_by(|(a, a_item), (b, b_item)| {
+            a.cmp(b)
+                .then_with(|| natural_cmp(&a_item.name, &b_item.name))
+        });
+
+        results
+            .into_iter()
+            .take(SEARCH_LIMIT)
+            .map(|(_, item)| item.clone())
+            .collect()
+    }
+}
+
+/// Whether `path` is `folder` or below it
+pub fn is_under(path: &str, folder: &str) -> bool {
+    folder == "/"
+        || path
+            .strip_prefix(folder)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// How well a word of the query matches a name, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Score {
+    /// The name starts with it
+    Prefix,
+    /// A word of the name starts with it
+    WordPrefix,
+    /// The name contains it
+    Substring,
+    /// A word of the name is a typo away from it
+    Typo,
+    /// Its letters appear in order in the name
+    Subsequence,
+}
+
+/// Match `term` against `name`, both lowercase
+fn score(name: &str, term: &str) -> Option<Score> {
+    if name.starts_with(term) {
+        return Some(Score::Prefix);
+    }
+
+    let mut words = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty());
+    if words.clone().any(|word| word.starts_with(term)) {
+        return Some(Score::WordPrefix);
+    }
+    if name.contains(term) {
+        return Some(Score::Substring);
+    }
+
+    let term_len = term.chars().count();
+    let max_distance = match term_len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    };
+    if max_distance > 0 && words.any(|word| within_distance(word, term, max_distance)) {
+        return Some(Score::Typo);
+    }
+
+    if term_len >= 3 {
+        let mut chars = name.chars();
+        if term.chars().all(|c| chars.any(|n| n == c)) {
+            return Some(Score::Subsequence);
+        }
+    }
+
+    None
+}
+
+/// Whether the edit distance between `a` and `b` is at most `max`
+fn within_distance(a: &str, b: &str, max: usize) -> bool {
+    let a: Vec<_> = a.chars().collect();
+    let b: Vec<_> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return false;
+    }
+
+    let mut previous: Vec<_> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()] <= max
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn file(full_path: &str) -> FileInfo {
+        let name = full_path.rsplit('/').next().unwrap();
+        FileInfo {
+            id: format!("id-{}", name),
+            name: name.to_string(),
+            size: 0,
+            last_modified_date_time: 0,
+            full_path: full_path.to_string(),
+            file_type: FileTypes::File,
+            etag: None,
+        }
+    }
+
+    fn names(results: Vec<FileInfo>) -> Vec<String> {
+        results.into_iter().map(|item| item.name).collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let index = SearchIndex::new("index.json.gz", String::new());
+        index.update(
+            "/",
+            vec![file("/The Expanse S01E10.mkv"), file("/expanse.nfo")],
+        );
+        index.update(
+            "/Shows",
+            vec![
+                file("/Shows/The Expanse S01E2.mkv"),
+                file("/Shows/Other.mkv"),
+            ],
+        );
+
+        assert_eq!(
+            names(index.search("expanse")),
+            vec![
+                "expanse.nfo",
+                "The Expanse S01E2.mkv",
+                "The Expanse S01E10.mkv"
+            ]
+        );
+        // Every word has to match
+        assert_eq!(
+            names(index.search("EXPANSE mkv")),
+            vec!["The Expanse S01E2.mkv", "The Expanse S01E10.mkv"]
+        );
+        // A typo, then letters in order
+        assert_eq!(names(index.search("expanze")).len(), 3);
+        assert_eq!(names(index.search("othmkv")), vec!["Other.mkv"]);
+        assert!(index.search("missing").is_empty());
+
+        index.retain(|folder| folder == "/");
+        assert_eq!(names(index.search("other")), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_within_distance() {
+        assert!(within_distance("expanse", "expanze", 1));
+        assert!(within_distance("expanse", "expnse", 1));
+        assert!(!within_distance("expanse", "epxanze", 1));
+        assert!(!within_distance("a", "abc", 1));
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json.gz");
+
+        let index = SearchIndex::new(&path, "drives".to_string());
+        index.update("/", vec![file("/a.mkv")]);
+        index.save().await.unwrap();
+
+        let loaded = SearchIndex::new(&path, "drives".to_string());
+        assert!(!loaded.is_ready());
+        loaded.load().await.unwrap();
+        assert!(loaded.is_ready());
+        assert_eq!(names(loaded.search("a.mkv")), vec!["a.mkv"]);
+
+        // Built with another config, the paths may point elsewhere now
+        let other = SearchIndex::new(&path, "other drives".to_string());
+        other.load().await.unwrap();
+        assert!(!other.is_ready());
+        assert!(other.search("a.mkv").is_empty());
+
+        // A missing index is empty rather than an error
+        let missing = SearchIndex::new(dir.path().join("missing.json.gz"), String::new());
+        missing.load().await.unwrap();
+        assert!(!missing.is_ready());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let index = SearchIndex::new("index.json.gz", String::new());
+        let mut shows = file("/Shows");
+        shows.file_type = FileTypes::Folder;
+        index.update("/", vec![shows, file("/a.mkv")]);
+        index.update("/Shows", vec![file("/Shows/b.mkv")]);
+        index.update("/Shows2", vec![]);
+
+        assert_eq!(index.subfolders("/"), vec!["/Shows"]);
+        let ids = HashSet::from(["id-b.mkv".to_string()]);
+        assert_eq!(index.folders_holding(&ids), vec!["/Shows"]);
+
+        index.invalidate("/Shows");
+        assert_eq!(index.take_stale(), vec!["/Shows"]);
+        assert!(index.take_stale().is_empty());
+        index.invalidate_tree("/");
+        assert_eq!(index.take_trees(), vec!["/"]);
+        assert!(index.take_trees().is_empty());
+
+        index.remove("/Shows");
+        assert!(!index.contains("/Shows"));
+        assert!(index.contains("/Shows2"));
+    }
+}
@@ -0,0 +1,286 @@
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use snafu::ResultExt;
+use tokio::fs;
+
+use crate::model::{local::parse_metadata, Caches, FileInfo, Thumbnails};
+
+use super::{Download, Error, IoSnafu, StorageBackend, SEARCH_LIMIT};
+
+/// Serves a directory tree on the local disk
+///
+/// Item ids are the URL-safe base64 of the path relative to `root`, so they
+/// can be used as a single URL segment just like OneDrive ids.
+#[derive(Debug)]
+pub struct LocalStorage {
+    /// What the drive is mounted as, scoping its cache keys
+    mount: String,
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(mount: &str, root: impl Into<PathBuf>) -> Self {
+        Self {
+            mount: mount.to_string(),
+            root: root.into(),
+        }
+    }
+
+    /// Resolve `path` to a location on disk, refusing anything outside `root`
+    async fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+        let not_found = || Error::LocationNotFound {
+            location: path.to_string(),
+        };
+
+        let relative = Path::new(path.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(not_found());
+        }
+
+        let root = fs::canonicalize(&self.root).await.context(IoSnafu)?;
+        let resolved = fs::canonicalize(root.join(relative))
+            .await
+            .map_err(|_| not_found())?;
+        if !resolved.starts_with(&root) {
+            return Err(not_found());
+        }
+
+        Ok(resolved)
+    }
+}
+
+/// Normalize `path` to the `full_path` form, `""` being the root
+fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(String::new(), |path, segment| path + "/" + segment)
+}
+
+fn encode_id(full_path: &str) -> String {
+    URL_SAFE_NO_PAD.encode(full_path)
+}
+
+fn decode_id(id: &str) -> Option<String> {
+    let path = URL_SAFE_NO_PAD.decode(id).ok()?;
+    String::from_utf8(path).ok()
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn list_children(&self, path: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error> {
+        let dir = self.resolve(path).await?;
+        let parent = normalize(path);
+
+        let mut entries = fs::read_dir(&dir).await.context(IoSnafu)?;
+        let mut children = Vec::new();
+        while let Some(entry) = entries.next_entry().await.context(IoSnafu)? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Ok(metadata) = fs::metadata(entry.path()).await else {
+                continue;
+            };
+
+            let full_path = format!("{}/{}", parent, name);
+            children.push(parse_metadata(
+                encode_id(&full_path),
+                full_path,
+                &metadata,
+                caches,
+                &self.mount,
+            ));
+        }
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(children)
+    }
+
+    async fn get_item(&self, path: &str, caches: &Caches) -> Result<FileInfo, Error> {
+        let resolved = self.resolve(path).await?;
+        let metadata = fs::metadata(&resolved).await.context(IoSnafu)?;
+
+        let full_path = normalize(path);
+        Ok(parse_metadata(
+            encode_id(&full_path),
+            full_path,
+            &metadata,
+            caches,
+            &self.mount,
+        ))
+    }
+
+    async fn get_item_by_id(&self, id: &str, caches: &Caches) -> Result<FileInfo, Error> {
+        let path = decode_id(id).ok_or_else(|| Error::ItemNotFound { id: id.to_string() })?;
+        self.get_item(&path, caches).await
+    }
+
+    async fn get_download(&self, id: &str, _: &Caches) -> Result<Download, Error> {
+        let not_found = || Error::ItemNotFound { id: id.to_string() };
+
+        let path = decode_id(id).ok_or_else(not_found)?;
+        let resolved = self.resolve(&path).await?;
+        if resolved.is_dir() {
+            return Err(not_found());
+        }
+
+        Ok(Download::File(resolved))
+    }
+
+    async fn get_thumbnails(&self, id: &str, _: &Caches) -> Result<Thumbnails, Error> {
+        Err(Error::ItemNotFound { id: id.to_string() })
+    }
+
+    async fn search(&self, query: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error> {
+        let query = query.to_lowercase();
+        let root = self.resolve("/").await?;
+
+        let mut results = Vec::new();
+        let mut folders = vec![(root, String::new())];
+        while let Some((dir, parent)) = folders.pop() {
+            let Ok(mut entries) = fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Some(entry) = entries.next_entry().await.context(IoSnafu)? {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                let full_path = format!("{}/{}", parent, name);
+
+                // Symlinked folders aren't followed, they may lead outside
+                // `root` or back up the tree
+                if entry.file_type().await.is_ok_and(|kind| kind.is_dir()) {
+                    folders.push((entry.path(), full_path.clone()));
+                }
+
+                if !name.to_lowercase().contains(&query) {
+                    continue;
+                }
+                let Ok(metadata) = fs::metadata(entry.path()).await else {
+                    continue;
+                };
+                results.push(parse_metadata(
+                    encode_id(&full_path),
+                    full_path,
+                    &metadata,
+                    caches,
+                    &self.mount,
+                ));
+                if results.len() >= SEARCH_LIMIT {
+                    return Ok(results);
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{model::FileTypes, utils::config::Setting};
+
+    use super::*;
+
+    fn caches() -> Caches {
+        Caches::new(Duration::from_secs(60))
+    }
+
+    fn storage() -> (tempfile::TempDir, LocalStorage) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("Movies")).unwrap();
+        std::fs::write(dir.path().join("Movies/a.mkv"), b"video").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"hello").unwrap();
+
+        let storage = LocalStorage::new("", dir.path());
+        (dir, storage)
+    }
+
+    #[tokio::test]
+    async fn test_list_children() {
+        let (_dir, storage) = storage();
+        let caches = caches();
+
+        let root = storage.list_children("/", &caches).await.unwrap();
+        assert_eq!(root.len(), 2);
+        assert_eq!(root[0].full_path, "/Movies");
+        assert!(matches!(root[0].file_type, FileTypes::Folder));
+        assert_eq!(root[1].size, 5);
+
+        let movies = storage.list_children("/Movies/", &caches).await.unwrap();
+        assert_eq!(movies[0].full_path, "/Movies/a.mkv");
+        assert!(matches!(movies[0].file_type, FileTypes::Video));
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let (dir, storage) = storage();
+
+        let item = storage.get_item("/Movies/a.mkv", &caches()).await.unwrap();
+        match storage.get_download(&item.id, &caches()).await.unwrap() {
+            Download::File(path) => {
+                assert_eq!(
+                    path,
+                    dir.path().join("Movies/a.mkv").canonicalize().unwrap()
+                )
+            }
+            download => panic!("unexpected download {:?}", download),
+        }
+
+        let folder = storage.get_item("/Movies", &caches()).await.unwrap();
+        assert!(storage.get_download(&folder.id, &caches()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let (_dir, storage) = storage();
+
+        let found = storage.search("A.MK", &caches()).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].full_path, "/Movies/a.mkv");
+        assert_eq!(found[0].id, encode_id("/Movies/a.mkv"));
+
+        assert!(storage
+            .search("missing", &caches())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_build() {
+        let (dir, _) = storage();
+        let config = Setting::parse(&format!("[local]\nroot = \"{}\"", dir.path().display()));
+
+        // A local folder configured at the top level is served at the root
+        let backend = crate::storage::build(&config.drives());
+        let movies = backend.list_children("/Movies", &caches()).await.unwrap();
+        assert_eq!(movies[0].full_path, "/Movies/a.mkv");
+        let item = backend
+            .get_item_by_id(&movies[0].id, &caches())
+            .await
+            .unwrap();
+        assert_eq!(item.size, 5);
+    }
+
+    #[tokio::test]
+    async fn test_outside_root() {
+        let (_dir, storage) = storage();
+
+        assert!(storage.list_children("/..", &caches()).await.is_err());
+        assert!(storage
+            .get_item("/Movies/../../etc", &caches())
+            .await
+            .is_err());
+        assert!(storage
+            .get_download(&encode_id("/../x"), &caches())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Storage backends the web layer reads from
+//!
+//! Every backend exposes its content relative to its own published root,
+//! so `"/"` is always the top of the listing regardless of `home_dir`.
+
+pub mod content;
+pub mod index;
+pub mod local;
+pub mod mount;
+pub mod onedrive;
+
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use snafu::Snafu;
+
+use crate::{
+    model::{Caches, FileInfo, Thumbnails},
+    utils::config::DriveSetting,
+};
+
+use self::{local::LocalStorage, mount::Mounts, onedrive::OnedriveStorage};
+
+#[async_trait]
+pub trait StorageBackend: Debug + Send + Sync {
+    /// List the children of the folder at `path`
+    async fn list_children(&self, path: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error>;
+
+    /// Get the item at `path`
+    async fn get_item(&self, path: &str, caches: &Caches) -> Result<FileInfo, Error>;
+
+    /// Get the item `id`, to find out where it lives before serving it
+    async fn get_item_by_id(&self, id: &str, caches: &Caches) -> Result<FileInfo, Error>;
+
+    /// Get where the content of the item `id` can be downloaded from
+    async fn get_download(&self, id: &str, caches: &Caches) -> Result<Download, Error>;
+
+    /// Get the thumbnails of the item `id`
+    async fn get_thumbnails(&self, id: &str, caches: &Caches) -> Result<Thumbnails, Error>;
+
+    /// Search the published content for items matching `query`, returning at
+    /// most `SEARCH_LIMIT` of them
+    async fn search(&self, query: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error>;
+}
+
+/// The most results a search returns
+pub const SEARCH_LIMIT: usize = 200;
+
+/// Build the backend serving `drives`, mounting each under its name unless a
+/// single drive is served at the root
+pub fn build(drives: &[DriveSetting]) -> Arc<dyn StorageBackend> {
+    let backend = |drive: &DriveSetting| -> Arc<dyn StorageBackend> {
+        match &drive.local {
+            Some(local) => Arc::new(LocalStorage::new(
+                &drive.mount,
+                Path::new(&local.root).join(drive.home_dir.trim_start_matches('/')),
+            )),
+            None => Arc::new(OnedriveStorage::new(&drive.mount, &drive.home_dir)),
+        }
+    };
+
+    if !is_mounted(drives) {
+        return backend(&drives[0]);
+    }
+
+    check_mounts(drives);
+    let mounts = drives
+        .iter()
+        .map(|drive| (drive.mount.clone(), backend(drive)))
+        .collect();
+    Arc::new(Mounts::new(mounts))
+}
+
+/// Refuse mount names that can't be told apart in paths and ids
+pub fn check_mounts(drives: &[DriveSetting]) {
+    if !is_mounted(drives) {
+        return;
+    }
+
+    let mut mounts = HashSet::new();
+    for drive in drives {
+        if drive.mount.is_empty() || drive.mount.contains(['/', ':']) {
+            panic!("Invalid mount name '{}'", drive.mount);
+        }
+        if !mounts.insert(&drive.mount) {
+            panic!("Duplicate mount name '{}'", drive.mount);
+        }
+    }
+}
+
+/// Whether `drives` are served under their mount names, rather than a single
+/// drive at the root
+pub fn is_mounted(drives: &[DriveSetting]) -> bool {
+    !matches!(drives, [drive] if drive.mount.is_empty())
+}
+
+/// Where the content of an item is served from
+#[derive(Debug, Clone)]
+pub enum Download {
+    /// A pre-authenticated URL
+    Url(String),
+    /// A file on the local disk
+    File(PathBuf),
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("Server still in the process of starting up"))]
+    StillStarting,
+
+    #[snafu(display("Drive '{}' needs an admin to log in again", mount))]
+    ReauthRequired { mount: String },
+
+    #[snafu(display("Location not found: {}", location))]
+    LocationNotFound { location: String },
+
+    #[snafu(display("Item not found: {}", id))]
+    ItemNotFound { id: String },
+
+    #[snafu(display("OneDrive request failed: {}", source))]
+    Onedrive { source: onedrive_api::Error },
+
+    #[snafu(display("Failed to read the local file: {}", source))]
+    Io { source: std::io::Error },
+
+    #[snafu(display("Failed to parse the item: {}", source))]
+    ParseItem { source: crate::model::item::Error },
+
+    #[snafu(display("Failed to parse the thumb: {}", source))]
+    ParseThumb { source: crate::model::thumb::Error },
+}
+
+impl Error {
+    /// Whether the item doesn't exist, or isn't published
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::LocationNotFound { .. } | Error::ItemNotFound { .. } => true,
+            Error::Onedrive { source } => {
+                source.status_code() == Some(reqwest::StatusCode::NOT_FOUND)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drive(mount: &str) -> DriveSetting {
+        DriveSetting {
+            mount: mount.to_string(),
+            home_dir: "/".to_string(),
+            auth: None,
+            local: None,
+        }
+    }
+
+    #[test]
+    fn test_check_mounts() {
+        check_mounts(&[drive("")]);
+        check_mounts(&[drive("music"), drive("movies")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate mount name 'music'")]
+    fn test_duplicate_mounts() {
+        check_mounts(&[drive("music"), drive("music")]);
+    }
+}
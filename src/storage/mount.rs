@@ -0,0 +1,208 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use tracing::warn;
+
+use crate::model::{Caches, FileInfo, FileTypes, Thumbnails};
+
+use super::{Download, Error, StorageBackend, SEARCH_LIMIT};
+
+/// Serves several backends side by side, each under `/{mount}`
+///
+/// Item ids are prefixed with `{mount}:` so downloads and thumbnails can be
+/// routed back to the drive the item came from.
+#[derive(Debug)]
+pub struct Mounts {
+    mounts: Vec<(String, Arc<dyn StorageBackend>)>,
+}
+
+impl Mounts {
+    pub fn new(mounts: Vec<(String, Arc<dyn StorageBackend>)>) -> Self {
+        Self { mounts }
+    }
+
+    fn backend(&self, mount: &str) -> Option<&Arc<dyn StorageBackend>> {
+        self.mounts
+            .iter()
+            .find(|(name, _)| name == mount)
+            .map(|(_, backend)| backend)
+    }
+
+    /// Split `path` into the mount name and the path inside that mount
+    fn split_path<'a>(
+        &self,
+        path: &'a str,
+    ) -> Result<(&'a str, &Arc<dyn StorageBackend>, String), Error> {
+        let path = path.trim_start_matches('/');
+        let (mount, rest) = path.split_once('/').unwrap_or((path, ""));
+        let backend = self.backend(mount).ok_or(Error::LocationNotFound {
+            location: format!("/{}", path),
+        })?;
+
+        Ok((mount, backend, format!("/{}", rest)))
+    }
+
+    /// Split `id` into the mount name and the id inside that mount
+    fn split_id<'a>(
+        &self,
+        id: &'a str,
+    ) -> Result<(&'a str, &Arc<dyn StorageBackend>, &'a str), Error> {
+        let not_found = || Error::ItemNotFound { id: id.to_string() };
+
+        let (mount, id) = id.split_once(':').ok_or_else(not_found)?;
+        let backend = self.backend(mount).ok_or_else(not_found)?;
+
+        Ok((mount, backend, id))
+    }
+}
+
+fn mount_info(mount: &str) -> FileInfo {
+    FileInfo {
+        id: mount.to_string(),
+        name: mount.to_string(),
+        size: 0,
+        last_modified_date_time: 0,
+        full_path: format!("/{}", mount),
+        file_type: FileTypes::Folder,
+        etag: None,
+    }
+}
+
+fn prefix(mount: &str, mut info: FileInfo) -> FileInfo {
+    info.id = format!("{}:{}", mount, info.id);
+    info.full_path = format!("/{}{}", mount, info.full_path);
+    info
+}
+
+#[async_trait]
+impl StorageBackend for Mounts {
+    async fn list_children(&self, path: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error> {
+        if path.trim_matches('/').is_empty() {
+            return Ok(self
+                .mounts
+                .iter()
+                .map(|(mount, _)| mount_info(mount))
+                .collect());
+        }
+
+        let (mount, backend, path) = self.split_path(path)?;
+        let children = backend.list_children(&path, caches).await?;
+
+        Ok(children
+            .into_iter()
+            .map(|child| prefix(mount, child))
+            .collect())
+    }
+
+    async fn get_item(&self, path: &str, caches: &Caches) -> Result<FileInfo, Error> {
+        let (mount, backend, path) = self.split_path(path)?;
+        if path.trim_matches('/').is_empty() {
+            return Ok(mount_info(mount));
+        }
+
+        let item = backend.get_item(&path, caches).await?;
+        Ok(prefix(mount, item))
+    }
+
+    async fn get_item_by_id(&self, id: &str, caches: &Caches) -> Result<FileInfo, Error> {
+        let (mount, backend, id) = self.split_id(id)?;
+        let item = backend.get_item_by_id(id, caches).await?;
+        Ok(prefix(mount, item))
+    }
+
+    async fn get_download(&self, id: &str, caches: &Caches) -> Result<Download, Error> {
+        let (_, backend, id) = self.split_id(id)?;
+        backend.get_download(id, caches).await
+    }
+
+    async fn get_thumbnails(&self, id: &str, caches: &Caches) -> Result<Thumbnails, Error> {
+        let (_, backend, id) = self.split_id(id)?;
+        backend.get_thumbnails(id, caches).await
+    }
+
+    async fn search(&self, query: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error> {
+        let searches = self
+            .mounts
+            .iter()
+            .map(|(mount, backend)| async move { (mount, backend.search(query, caches).await) });
+
+        // A drive waiting for a login shouldn't hide the results of the others
+        let mut results = Vec::new();
+        for (mount, found) in join_all(searches).await {
+            match found {
+                Ok(found) => results.extend(found.into_iter().map(|item| prefix(mount, item))),
+                Err(e) => warn!("Failed to search '{}': {}", mount, e),
+            }
+        }
+        results.truncate(SEARCH_LIMIT);
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::storage::local::LocalStorage;
+
+    use super::*;
+
+    fn caches() -> Caches {
+        Caches::new(Duration::from_secs(60))
+    }
+
+    #[tokio::test]
+    async fn test_mounts() {
+        let music = tempfile::tempdir().unwrap();
+        std::fs::write(music.path().join("song.mp3"), b"la").unwrap();
+        let movies = tempfile::tempdir().unwrap();
+        std::fs::write(movies.path().join("song.mp3"), b"not a song").unwrap();
+
+        let mounts = Mounts::new(vec![
+            (
+                "music".to_string(),
+                Arc::new(LocalStorage::new("music", music.path())),
+            ),
+            (
+                "movies".to_string(),
+                Arc::new(LocalStorage::new("movies", movies.path())),
+            ),
+        ]);
+        let caches = caches();
+
+        let root = mounts.list_children("/", &caches).await.unwrap();
+        assert_eq!(root.len(), 2);
+        assert_eq!(root[1].full_path, "/movies");
+
+        let songs = mounts.list_children("/music", &caches).await.unwrap();
+        assert_eq!(songs[0].full_path, "/music/song.mp3");
+        assert!(songs[0].id.starts_with("music:"));
+
+        let item = mounts.get_item("/movies/song.mp3", &caches).await.unwrap();
+        assert_eq!(item.size, 10);
+        let by_id = mounts.get_item_by_id(&item.id, &caches).await.unwrap();
+        assert_eq!(by_id.full_path, "/movies/song.mp3");
+        match mounts.get_download(&item.id, &caches).await.unwrap() {
+            Download::File(path) => {
+                assert!(path.starts_with(movies.path().canonicalize().unwrap()))
+            }
+            download => panic!("unexpected download {:?}", download),
+        }
+
+        let found = mounts.search("SONG", &caches).await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].full_path, "/movies/song.mp3");
+
+        // The same path on both drives is cached apart
+        let local_id = item.id.trim_start_matches("movies:");
+        let cached = |key: String| caches.file_cache.get(&key).unwrap().size;
+        assert_eq!(cached(format!("movies:{}", local_id)), 10);
+        mounts.get_item("/music/song.mp3", &caches).await.unwrap();
+        assert_eq!(cached(format!("music:{}", local_id)), 2);
+
+        assert!(mounts.list_children("/books", &caches).await.is_err());
+        assert!(mounts.get_download("books:x", &caches).await.is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use onedrive_api::{
+    option::{CollectionOption, ObjectOption},
+    resource::{DriveItem, DriveItemField},
+    ItemId, ItemLocation, ListChildrenFetcher,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use snafu::ResultExt;
+
+use crate::{
+    model::{
+        cache_key,
+        item::{parent_path, parse_item},
+        thumb::parse_thumb,
+        Caches, FileInfo, Thumbnails,
+    },
+    onedrive::{Onedrive, TokenStatus},
+    utils::config::DriveLocation,
+    DRIVES,
+};
+
+use super::{
+    Download, Error, OnedriveSnafu, ParseItemSnafu, ParseThumbSnafu, StorageBackend, SEARCH_LIMIT,
+};
+
+/// Children asked for per page, the most Graph hands out at once
+const PAGE_SIZE: usize = 1000;
+/// Search results looked up at once, so a search doesn't get the drives
+/// throttled
+const SEARCH_CONCURRENCY: usize = 8;
+
+/// Serves the subtree under `home_dir` of the drive stored in `DRIVES`
+#[derive(Debug)]
+pub struct OnedriveStorage {
+    mount: String,
+    home_dir: String,
+}
+
+impl OnedriveStorage {
+    pub fn new(mount: &str, home_dir: &str) -> Self {
+        let home_dir = home_dir.trim_end_matches('/');
+        let home_dir = if home_dir.is_empty() || home_dir.starts_with('/') {
+            home_dir.to_string()
+        } else {
+            format!("/{}", home_dir)
+        };
+
+        Self {
+            mount: mount.to_string(),
+            home_dir,
+        }
+    }
+
+    fn drive(&self) -> Result<Arc<Onedrive>, Error> {
+        let drive = DRIVES
+            .get()
+            .and_then(|drives| drives.get(&self.mount))
+            .map(|drive| drive.load_full())
+            .ok_or(Error::StillStarting)?;
+
+        match drive.status {
+            TokenStatus::ReauthRequired { .. } => Err(Error::ReauthRequired {
+                mount: self.mount.clone(),
+            }),
+            _ => Ok(drive),
+        }
+    }
+
+    async fn get_by_id(
+        &self,
+        id: &str,
+        option: ObjectOption<DriveItemField>,
+    ) -> Result<DriveItem, Error> {
+        self.drive()?
+            .drive
+            .get_item_with_option(ItemLocation::from_id(&ItemId(id.to_owned())), option)
+            .await
+            .context(OnedriveSnafu)?
+            .ok_or(Error::ItemNotFound { id: id.to_string() })
+    }
+
+    /// The same drive may be mounted twice with different `home_dir`s, so
+    /// its items are cached under the mount
+    pub fn cache_key(&self, id: &str) -> String {
+        cache_key(&self.mount, id)
+    }
+
+    /// Refuse items outside `home_dir`, so that knowing an id doesn't give
+    /// access to the rest of the drive
+    fn check_published(&self, id: &str, item: &DriveItem, caches: &Caches) -> Result<(), Error> {
+        let published = self.is_home(parent_path(item));
+        caches.published_cache.insert(self.cache_key(id), published);
+        if published {
+            Ok(())
+        } else {
+            Err(Error::ItemNotFound { id: id.to_string() })
+        }
+    }
+
+    /// Whether `item` may lie under `home_dir`, judging by the parent path it
+    /// came with; items without one, as delta items often are, may
+    pub fn may_publish(&self, item: &DriveItem) -> bool {
+        let has_path = item
+            .parent_reference
+            .as_ref()
+            .is_some_and(|parent| parent.get("path").is_some());
+        !has_path || self.is_home(parent_path(item))
+    }
+
+    /// Whether `folder` is `home_dir` or lies under it
+    fn is_home(&self, folder: &str) -> bool {
+        // OneDrive paths are case-insensitive
+        let folder = folder.to_lowercase();
+        let home_dir = self.home_dir.to_lowercase();
+        folder
+            .strip_prefix(&home_dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// The id of the `home_dir` folder, which no listing contains
+    pub async fn home_id(&self) -> Result<String, Error> {
+        let dir = self.drive_path("/");
+        let item_location = ItemLocation::from_path(&dir).ok_or(Error::LocationNotFound {
+            location: dir.clone(),
+        })?;
+
+        let option = ObjectOption::default().select(&[DriveItemField::id]);
+        self.drive()?
+            .drive
+            .get_item_with_option(item_location, option)
+            .await
+            .context(OnedriveSnafu)?
+            .and_then(|item| item.id)
+            .map(|id| id.0)
+            .ok_or(Error::ItemNotFound { id: dir })
+    }
+
+    /// The items of the whole drive changed since `delta_url`, along with the
+    /// URL to ask for the next changes; without a URL to start from, only the
+    /// URL is returned
+    pub async fn changes(
+        &self,
+        delta_url: Option<&str>,
+    ) -> Result<(Vec<DriveItem>, String), Error> {
+        let drive = self.drive()?;
+        match delta_url {
+            Some(delta_url) => drive
+                .drive
+                .track_root_changes_from_delta_url(delta_url)
+                .await
+                .context(OnedriveSnafu)?
+                .fetch_all(&drive.drive)
+                .await
+                .context(OnedriveSnafu),
+            None => {
+                let delta_url = drive
+                    .drive
+                    .get_root_latest_delta_url()
+                    .await
+                    .context(OnedriveSnafu)?;
+                Ok((Vec::new(), delta_url))
+            }
+        }
+    }
+
+    /// The Graph URL searching the published subtree of the drive at
+    /// `location` for `query`
+    fn search_url(&self, location: &DriveLocation, query: &str) -> String {
+        let encode = |s: &str| utf8_percent_encode(s, NON_ALPHANUMERIC).to_string();
+        let drive = match location {
+            DriveLocation::Me => "/me/drive".to_string(),
+            DriveLocation::User(id) => format!("/users/{}/drive", encode(id)),
+            DriveLocation::Group(id) => format!("/groups/{}/drive", encode(id)),
+            DriveLocation::Site(id) => format!("/sites/{}/drive", encode(id)),
+            DriveLocation::Drive(id) => format!("/drives/{}", encode(id)),
+        };
+        let folder = match self.home_dir.as_str() {
+            "" => "/root".to_string(),
+            home_dir => {
+                let home_dir = home_dir
+                    .split('/')
+                    .map(encode)
+                    .collect::<Vec<_>>()
+                    .join("/");
+                format!("/root:{}:", home_dir)
+            }
+        };
+        // Quotes are doubled in OData string literals
+        let query = encode(&query.replace('\'', "''"));
+
+        format!(
+            "https://graph.microsoft.com/v1.0{}{}/search(q='{}')",
+            drive, folder, query
+        )
+    }
+
+    /// Keep the search result `item` if it lies under `home_dir`, looking it
+    /// up when Graph leaves out its parent path, as it does for business drives
+    async fn published_result(&self, item: DriveItem, caches: &Caches) -> Option<FileInfo> {
+        let id = item.id.as_ref()?.0.clone();
+        let item = if parent_path(&item).is_empty() {
+            let option = ObjectOption::default().expand(DriveItemField::thumbnails, None);
+            self.get_by_id(&id, option).await.ok()?
+        } else {
+            item
+        };
+
+        self.check_published(&id, &item, caches).ok()?;
+        parse_item(&item, caches, &self.mount, &self.home_dir).ok()
+    }
+
+    /// Join `path` onto `home_dir`, giving the absolute path in the drive
+    fn drive_path(&self, path: &str) -> String {
+        let path = path.trim_end_matches('/');
+        match (self.home_dir.as_str(), path) {
+            ("", "") => "/".to_string(),
+            (home_dir, path) => format!("{}{}", home_dir, path),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for OnedriveStorage {
+    async fn list_children(&self, path: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error> {
+        let dir = self.drive_path(path);
+        let item_location = ItemLocation::from_path(&dir).ok_or(Error::LocationNotFound {
+            location: dir.clone(),
+        })?;
+
+        let drive = self.drive()?;
+        let option = CollectionOption::default().page_size(PAGE_SIZE);
+        let mut fetcher = drive
+            .drive
+            .list_children_with_option(item_location, option)
+            .await
+            .context(OnedriveSnafu)?
+            .ok_or(Error::ItemNotFound { id: dir })?;
+
+        // Graph answers with pages linked by `@odata.nextLink`, follow them
+        // all so large folders aren't cut short
+        let mut children = Vec::new();
+        while let Some(page) = fetcher
+            .fetch_next_page(&drive.drive)
+            .await
+            .context(OnedriveSnafu)?
+        {
+            children.extend(
+                page.iter()
+                    .filter_map(|item| parse_item(item, caches, &self.mount, &self.home_dir).ok()),
+            );
+        }
+
+        Ok(children)
+    }
+
+    async fn get_item(&self, path: &str, caches: &Caches) -> Result<FileInfo, Error> {
+        let dir = self.drive_path(path);
+        let item_location = ItemLocation::from_path(&dir).ok_or(Error::LocationNotFound {
+            location: dir.clone(),
+        })?;
+
+        let option = ObjectOption::default().expand(DriveItemField::thumbnails, None);
+        let item = self
+            .drive()?
+            .drive
+            .get_item_with_option(item_location, option)
+            .await
+            .context(OnedriveSnafu)?
+            .ok_or(Error::ItemNotFound { id: dir })?;
+
+        parse_item(&item, caches, &self.mount, &self.home_dir).context(ParseItemSnafu)
+    }
+
+    async fn get_item_by_id(&self, id: &str, caches: &Caches) -> Result<FileInfo, Error> {
+        match caches.published_cache.get(&self.cache_key(id)) {
+            Some(true) => {
+                if let Some(item) = caches.file_cache.get(&self.cache_key(id)) {
+                    return Ok((*item).clone());
+                }
+            }
+            Some(false) => return Err(Error::ItemNotFound { id: id.to_string() }),
+            None => {}
+        }
+
+        let option = ObjectOption::default().expand(DriveItemField::thumbnails, None);
+        let item = self.get_by_id(id, option).await?;
+        self.check_published(id, &item, caches)?;
+
+        parse_item(&item, caches, &self.mount, &self.home_dir).context(ParseItemSnafu)
+    }
+
+    async fn get_download(&self, id: &str, caches: &Caches) -> Result<Download, Error> {
+        match caches.published_cache.get(&self.cache_key(id)) {
+            Some(true) => {}
+            Some(false) => return Err(Error::ItemNotFound { id: id.to_string() }),
+            None => {
+                let option = ObjectOption::default().select(&[DriveItemField::parent_reference]);
+                let item = self.get_by_id(id, option).await?;
+                self.check_published(id, &item, caches)?;
+            }
+        }
+
+        self.drive()?
+            .drive
+            .get_item_download_url(ItemLocation::from_id(&ItemId(id.to_owned())))
+            .await
+            .map(Download::Url)
+            .context(OnedriveSnafu)
+    }
+
+    async fn get_thumbnails(&self, id: &str, caches: &Caches) -> Result<Thumbnails, Error> {
+        let option = ObjectOption::default().expand(DriveItemField::thumbnails, None);
+        let item = self.get_by_id(id, option).await?;
+        self.check_published(id, &item, caches)?;
+
+        match &item.thumbnails {
+            Some(thumbnails) => parse_thumb(thumbnails).context(ParseThumbSnafu),
+            None => Err(Error::ItemNotFound { id: id.to_string() }),
+        }
+    }
+
+    async fn search(&self, query: &str, caches: &Caches) -> Result<Vec<FileInfo>, Error> {
+        let drive = self.drive()?;
+
+        // onedrive_api has no search, but its fetcher follows any collection
+        let url = self.search_url(&drive.setting.location, query);
+        let mut fetcher = ListChildrenFetcher::resume_from(url);
+        let mut items = Vec::new();
+        while items.len() < SEARCH_LIMIT {
+            match fetcher
+                .fetch_next_page(&drive.drive)
+                .await
+                .context(OnedriveSnafu)?
+            {
+                Some(page) => items.extend(page),
+                None => break,
+            }
+        }
+        items.truncate(SEARCH_LIMIT);
+
+        // Searching a folder may still turn up items elsewhere in the drive
+        let results: Vec<_> = stream::iter(items)
+            .map(|item| self.published_result(item, caches))
+            .buffered(SEARCH_CONCURRENCY)
+            .collect()
+            .await;
+
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn item(parent: &str) -> DriveItem {
+        serde_json::from_value(serde_json::json!({ "parentReference": { "path": parent } }))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_published() {
+        let storage = OnedriveStorage::new("movies", "/Movies");
+        let caches = Caches::new(Duration::from_secs(60));
+        let check = |id, parent| storage.check_published(id, &item(parent), &caches);
+
+        assert!(check("a", "/drive/root:/Movies").is_ok());
+        assert!(check("b", "/drives/x/root:/movies/2024").is_ok());
+        assert!(check("c", "/drive/root:/MoviesX").is_err());
+        assert!(check("d", "/drive/root:").is_err());
+        assert_eq!(
+            caches.published_cache.get(&"movies:c".to_string()),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_may_publish() {
+        let storage = OnedriveStorage::new("movies", "/Movies");
+
+        assert!(storage.may_publish(&item("/drive/root:/movies/2024")));
+        assert!(!storage.may_publish(&item("/drive/root:/Music")));
+        assert!(!storage.may_publish(&item("/drive/root:")));
+        // Without a path, only a lookup can tell
+        let item: DriveItem =
+            serde_json::from_value(serde_json::json!({ "parentReference": { "id": "x" } }))
+                .unwrap();
+        assert!(storage.may_publish(&item));
+    }
+
+    #[tokio::test]
+    async fn test_cache_per_mount() {
+        let item: DriveItem = serde_json::from_value(serde_json::json!({
+            "id": "x",
+            "name": "a.mkv",
+            "parentReference": { "path": "/drive/root:/Movies/New" },
+        }))
+        .unwrap();
+        let movies = OnedriveStorage::new("movies", "/Movies");
+        let music = OnedriveStorage::new("music", "/Music");
+        let caches = Caches::new(Duration::from_secs(60));
+
+        // The same drive mounted twice, the item cached through one mount
+        parse_item(&item, &caches, &movies.mount, &movies.home_dir).unwrap();
+        caches.published_cache.insert(movies.cache_key("x"), true);
+        assert!(caches.file_cache.get(&movies.cache_key("x")).is_some());
+
+        let cached = movies.get_item_by_id("x", &caches).await.unwrap();
+        assert_eq!(cached.full_path, "/New/a.mkv");
+        // Outside the other mount's home_dir, which doesn't take the entry
+        // cached for the first one
+        caches.published_cache.insert(music.cache_key("x"), false);
+        let refused = music.get_item_by_id("x", &caches).await;
+        assert!(matches!(refused, Err(Error::ItemNotFound { .. })));
+        assert!(matches!(
+            music.get_download("x", &caches).await,
+            Err(Error::ItemNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_search_url() {
+        let storage = OnedriveStorage::new("", "/My Movies");
+        assert_eq!(
+            storage.search_url(&DriveLocation::Me, "it's"),
+            "https://graph.microsoft.com/v1.0/me/drive/root:/My%20Movies:/search(q='it%27%27s')"
+        );
+
+        let storage = OnedriveStorage::new("", "");
+        assert_eq!(
+            storage.search_url(&DriveLocation::Drive("b!x".to_string()), "a"),
+            "https://graph.microsoft.com/v1.0/drives/b%21x/root/search(q='a')"
+        );
+    }
+}
@@ -12,16 +12,79 @@ static CONFIG_PATH: &str = "config.toml";
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Setting {
-    pub auth: Auth,
+    pub auth: Option<Auth>,
+    /// Serve a local directory instead of OneDrive
+    pub local: Option<LocalSetting>,
+    /// Drives mounted under their own name, replacing `auth` and `local`
+    #[serde(default)]
+    pub drives: Vec<DriveSetting>,
+    /// Folders that need a password
+    #[serde(default)]
+    pub protect: Vec<ProtectSetting>,
+    /// Items left out of listings and refused when requested
+    #[serde(default)]
+    pub hide: HideSetting,
+    /// Local index answering searches
+    #[serde(default)]
+    pub index: IndexSetting,
+    /// Which downloads are proxied rather than redirected, the first
+    /// matching rule winning over `use_proxy`
+    #[serde(default)]
+    pub proxy_rules: Vec<ProxyRuleSetting>,
     pub setting: UserSetting,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DriveSetting {
+    /// The name the drive is listed under, empty to serve it at the root
+    pub mount: String,
+    #[serde(default = "default_home_dir")]
+    pub home_dir: String,
+    pub auth: Option<Auth>,
+    pub local: Option<LocalSetting>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Auth {
     pub client_id: String,
     pub client_secret: String,
     pub refresh_token: Option<String>,
     pub r#type: ApiType,
+    /// Which drive of the account to serve
+    #[serde(default)]
+    pub location: DriveLocation,
+    /// How to sign in when there is no refresh token
+    #[serde(default)]
+    pub login: LoginMethod,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    /// Open a URL in a browser that can reach the redirect listener on port 8077
+    #[default]
+    Redirect,
+    /// Enter a code on any device, for headless servers
+    DeviceCode,
+    /// App-only access with the client secret, no user sign-in at all
+    ClientCredentials,
+}
+
+/// Selects the drive to serve, e.g. `location = { site = "<site id>" }`
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DriveLocation {
+    /// The OneDrive of the signed-in user
+    #[default]
+    Me,
+    /// The OneDrive of a user, by id or principal name
+    User(String),
+    /// The document library of a Microsoft 365 group
+    Group(String),
+    /// The default document library of a SharePoint site
+    Site(String),
+    /// A drive by its id, e.g. a non-default document library
+    Drive(String),
 }
 
 #[derive(Debug, Clone)]
@@ -63,15 +126,244 @@ impl<'de> Deserialize<'de> for ApiType {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LocalSetting {
+    /// The directory to publish
+    pub root: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProtectSetting {
+    /// The published path of the folder, covering everything below it
+    pub path: String,
+    /// The argon2 hash of the password, see `onelist hash-password`
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct HideSetting {
+    /// Globs on the full path, or on the name when they have no `/`
+    #[serde(default)]
+    pub glob: Vec<String>,
+    /// Regexes on the full path
+    #[serde(default)]
+    pub regex: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProxyRuleSetting {
+    /// The published path of the folder, covering everything below it
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Files of at least this many bytes
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    /// Files of at most this many bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Client networks, e.g. `192.168.0.0/16`
+    #[serde(default)]
+    pub network: Vec<String>,
+    /// Proxy the matching downloads, or redirect them
+    pub proxy: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IndexSetting {
+    /// Crawl the published tree in the background and search it locally
+    #[serde(default)]
+    pub enable: bool,
+    /// Where the index is kept between restarts
+    #[serde(default = "default_index_path")]
+    pub path: String,
+    /// Seconds between two crawls when local folders are published, the
+    /// changes of OneDrive drives being followed as they happen
+    #[serde(default = "default_index_interval")]
+    pub interval: u64,
+}
+
+impl Default for IndexSetting {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            path: default_index_path(),
+            interval: default_index_interval(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct UserSetting {
+    #[serde(default = "default_home_dir")]
     pub home_dir: String,
     pub use_proxy: bool,
     pub name: String,
     pub port: u16,
+    /// Where the rotated refresh tokens are kept, so `config.toml` can be
+    /// read-only
+    #[serde(default = "default_token_store")]
+    pub token_store: String,
+    /// Bearer token for the `/api/admin` endpoints, which are disabled
+    /// when unset
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Key signing the tokens handed out by the server, random on every
+    /// start when unset
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Time to live and capacity of each cache
+    #[serde(default, skip_serializing_if = "CacheSettings::is_empty")]
+    pub cache: CacheSettings,
+    /// Limits on the downloads streamed through the server
+    #[serde(default, skip_serializing_if = "ProxySetting::is_empty")]
+    pub proxy: ProxySetting,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CacheSettings {
+    /// Download URLs, kept for their TTL unless they expire sooner
+    #[serde(default, skip_serializing_if = "CacheSetting::is_empty")]
+    pub download_url: CacheSetting,
+    /// Folder contents, the capacity counting their items
+    #[serde(default, skip_serializing_if = "CacheSetting::is_empty")]
+    pub list: CacheSetting,
+    #[serde(default, skip_serializing_if = "CacheSetting::is_empty")]
+    pub thumb: CacheSetting,
+    #[serde(default, skip_serializing_if = "CacheSetting::is_empty")]
+    pub file: CacheSetting,
+    /// Whether item ids lie under `home_dir`
+    #[serde(default, skip_serializing_if = "CacheSetting::is_empty")]
+    pub published: CacheSetting,
+    /// Where listings, file info and thumbnails are saved so they survive
+    /// restarts, not saved when unset
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// Seconds between two snapshots, besides the one on shutdown
+    #[serde(default)]
+    pub snapshot_interval: Option<u64>,
+}
+
+impl CacheSettings {
+    /// The config crate reads empty tables back as unit, so leave them out
+    fn is_empty(&self) -> bool {
+        [
+            &self.download_url,
+            &self.list,
+            &self.thumb,
+            &self.file,
+            &self.published,
+        ]
+        .iter()
+        .all(|setting| setting.is_empty())
+            && self.snapshot.is_none()
+            && self.snapshot_interval.is_none()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CacheSetting {
+    /// Seconds an entry is kept, the built-in default when unset
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// The most entries kept, the built-in default when unset
+    #[serde(default)]
+    pub capacity: Option<u64>,
+}
+
+impl CacheSetting {
+    fn is_empty(&self) -> bool {
+        self.ttl.is_none() && self.capacity.is_none()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProxySetting {
+    /// The most proxied downloads streaming at once, unlimited when unset
+    #[serde(default)]
+    pub max_streams: Option<usize>,
+    /// Bytes per second shared by all proxied downloads
+    #[serde(default)]
+    pub rate: Option<u64>,
+    /// Bytes per second for the proxied downloads of each client
+    #[serde(default)]
+    pub client_rate: Option<u64>,
+    /// Take the client's address from the `X-Forwarded-For` header of a
+    /// reverse proxy in front
+    #[serde(default)]
+    pub trust_forwarded: bool,
+    /// Where the content of proxied downloads is cached, not cached when
+    /// unset
+    #[serde(default)]
+    pub cache_dir: Option<String>,
+    /// The most bytes the content cache keeps on disk
+    #[serde(default)]
+    pub cache_size: Option<u64>,
+}
+
+impl ProxySetting {
+    fn is_empty(&self) -> bool {
+        self.max_streams.is_none()
+            && self.rate.is_none()
+            && self.client_rate.is_none()
+            && !self.trust_forwarded
+            && self.cache_dir.is_none()
+            && self.cache_size.is_none()
+    }
+}
+
+fn default_home_dir() -> String {
+    "/".to_string()
+}
+
+fn default_token_store() -> String {
+    "tokens.toml".to_string()
+}
+
+fn default_index_path() -> String {
+    "index.json.gz".to_string()
+}
+
+fn default_index_interval() -> u64 {
+    60 * 60
 }
 
 impl Setting {
+    /// The drives to serve, the top-level `auth` or `local` being a single
+    /// drive at the root when no `drives` are configured
+    pub fn drives(&self) -> Vec<DriveSetting> {
+        if !self.drives.is_empty() {
+            return self.drives.clone();
+        }
+
+        vec![DriveSetting {
+            mount: String::new(),
+            home_dir: self.setting.home_dir.clone(),
+            auth: self.auth.clone(),
+            local: self.local.clone(),
+        }]
+    }
+
+    /// A config with the required settings, followed by `extra`, for tests
+    #[cfg(test)]
+    pub fn parse(extra: &str) -> Self {
+        let toml = format!(
+            r#"
+            [setting]
+            use_proxy = false
+            name = "name"
+            port = 3000
+            {}
+            "#,
+            extra
+        );
+        Config::builder()
+            .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
     pub fn load() -> Result<Self, Error> {
         let settings = Config::builder()
             // Add in `./Settings.toml`
@@ -84,15 +376,7 @@ impl Setting {
         settings.try_deserialize().context(ConfigParseFailedSnafu)
     }
 
-    pub async fn save(&mut self) -> Result<(), Error> {
-        // update the refresh token
-        if let Some(drive) = crate::DRIVE.get() {
-            let refresh_token = drive.load().token.refresh_token.clone();
-            if let Some(refresh_token) = refresh_token {
-                self.auth.refresh_token = Some(refresh_token);
-            }
-        }
-
+    pub async fn save(&self) -> Result<(), Error> {
         let toml = toml::to_string(self).unwrap();
 
         tokio::fs::write(CONFIG_PATH, toml)
@@ -114,18 +398,31 @@ pub async fn handle_error(e: Error) {
         }
 
         // create a new config file
-        let mut new_config = Setting {
-            auth: Auth {
+        let new_config = Setting {
+            auth: Some(Auth {
                 client_id: "".to_string(),
                 client_secret: "".to_string(),
                 refresh_token: None,
                 r#type: ApiType(Tenant::Consumers),
-            },
+                location: DriveLocation::Me,
+                login: LoginMethod::Redirect,
+            }),
+            local: None,
+            drives: Vec::new(),
+            protect: Vec::new(),
+            hide: HideSetting::default(),
+            index: IndexSetting::default(),
+            proxy_rules: Vec::new(),
             setting: UserSetting {
                 home_dir: "/".to_string(),
                 use_proxy: false,
                 name: "Onelist".to_string(),
                 port: 3000,
+                token_store: default_token_store(),
+                admin_token: None,
+                secret: None,
+                cache: CacheSettings::default(),
+                proxy: ProxySetting::default(),
             },
         };
 
@@ -140,26 +437,119 @@ mod tests {
 
     #[tokio::test]
     async fn test_setting() {
-        let mut setting = Setting {
-            auth: Auth {
+        let setting = Setting {
+            auth: Some(Auth {
                 client_id: "client_id".to_string(),
                 client_secret: "client_secret".to_string(),
                 refresh_token: None,
                 r#type: ApiType(Tenant::Consumers),
-            },
+                location: DriveLocation::Me,
+                login: LoginMethod::Redirect,
+            }),
+            local: None,
+            drives: Vec::new(),
+            protect: Vec::new(),
+            hide: HideSetting::default(),
+            index: IndexSetting::default(),
+            proxy_rules: Vec::new(),
             setting: UserSetting {
                 home_dir: "/".to_string(),
                 use_proxy: false,
                 name: "name".to_string(),
                 port: 3000,
+                token_store: default_token_store(),
+                admin_token: None,
+                secret: None,
+                cache: CacheSettings::default(),
+                proxy: ProxySetting::default(),
             },
         };
 
         setting.save().await.unwrap();
 
         let loaded_setting = Setting::load().unwrap();
-        assert_eq!(setting.auth.client_id, loaded_setting.auth.client_id);
+        assert_eq!(
+            setting.auth.unwrap().client_id,
+            loaded_setting.auth.unwrap().client_id
+        );
 
         std::fs::remove_file(CONFIG_PATH).unwrap();
     }
+
+    #[test]
+    fn test_deserialize() {
+        let setting = Setting::parse(
+            r#"
+            [auth]
+            client_id = "client_id"
+            client_secret = "client_secret"
+            type = "consumers"
+
+            [[protect]]
+            path = "/private"
+            password = "hash"
+            "#,
+        );
+
+        assert_eq!(setting.setting.name, "name");
+        assert_eq!(setting.setting.port, 3000);
+        assert_eq!(setting.setting.home_dir, "/");
+        assert_eq!(setting.setting.token_store, "tokens.toml");
+        assert_eq!(setting.protect.len(), 1);
+        assert!(!setting.index.enable);
+
+        // Without `drives`, the top-level auth is a single drive at the root
+        let drives = setting.drives();
+        assert_eq!(drives.len(), 1);
+        assert_eq!(drives[0].mount, "");
+        let auth = drives[0].auth.as_ref().unwrap();
+        assert_eq!(auth.client_id, "client_id");
+        assert_eq!(auth.location, DriveLocation::Me);
+        assert!(drives[0].local.is_none());
+    }
+
+    #[test]
+    fn test_drive_location() {
+        let setting = Setting::parse(
+            r#"
+            [[drives]]
+            mount = "me"
+            [drives.auth]
+            client_id = "client_id"
+            client_secret = "client_secret"
+            type = "organizations"
+
+            [[drives]]
+            mount = "team"
+            [drives.auth]
+            client_id = "client_id"
+            client_secret = "client_secret"
+            type = "organizations"
+            location = { site = "contoso.sharepoint.com,1,2" }
+            "#,
+        );
+
+        let drives = setting.drives();
+        assert_eq!(drives[0].auth.as_ref().unwrap().location, DriveLocation::Me);
+        assert_eq!(
+            drives[1].auth.as_ref().unwrap().location,
+            DriveLocation::Site("contoso.sharepoint.com,1,2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cache_settings() {
+        let setting = Setting::parse(
+            r#"
+            [setting.cache.list]
+            ttl = 60
+            capacity = 1000
+            "#,
+        );
+
+        let cache = &setting.setting.cache;
+        assert_eq!(cache.list.ttl, Some(60));
+        assert_eq!(cache.list.capacity, Some(1000));
+        assert!(cache.download_url.is_empty());
+    }
 }
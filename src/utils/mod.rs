@@ -1 +1,6 @@
 pub mod config;
+pub mod gzip;
+pub mod natural;
+pub mod password;
+pub mod sign;
+pub mod token_store;
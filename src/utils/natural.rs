@@ -0,0 +1,71 @@
+//! Natural ordering of names, so that `Episode 2` comes before `Episode 10`
+
+use std::{cmp::Ordering, iter::Peekable, str::Chars};
+
+/// Compare `a` and `b` with runs of digits compared by value and the rest
+/// case-insensitively
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        let ordering = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a_chars);
+                let y = take_number(&mut b_chars);
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(&x), Some(&y)) => {
+                a_chars.next();
+                b_chars.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        number.push(digit);
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "Episode 10.mkv",
+            "episode 2.mkv",
+            "Episode 1.mkv",
+            "Episode 02.mkv",
+            "Extras",
+            "Episode",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            vec![
+                "Episode",
+                "Episode 1.mkv",
+                "Episode 02.mkv",
+                "episode 2.mkv",
+                "Episode 10.mkv",
+                "Extras",
+            ]
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! Password hashes for protected folders
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+
+/// Hash `password` into the PHC string stored in the config
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Check `password` against a PHC string, `None` if the hash is malformed
+pub fn verify_password(password: &str, hash: &str) -> Option<bool> {
+    let hash = PasswordHash::new(hash).ok()?;
+    Some(
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password() {
+        let hash = hash_password("hunter2");
+
+        assert_eq!(verify_password("hunter2", &hash), Some(true));
+        assert_eq!(verify_password("hunter3", &hash), Some(false));
+        assert_eq!(verify_password("hunter2", "hunter2"), None);
+    }
+}
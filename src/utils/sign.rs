@@ -0,0 +1,65 @@
+//! HMAC signatures for the tokens the server hands out
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    /// Sign with `secret`, or with a random key when unset, which invalidates
+    /// every token on restart
+    pub fn new(secret: Option<&str>) -> Self {
+        let key = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+
+        Self { key }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// The URL-safe signature of `payload`
+    pub fn sign(&self, payload: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(payload).finalize().into_bytes())
+    }
+
+    /// Check `signature` against `payload` in constant time
+    pub fn verify(&self, payload: &str, signature: &str) -> bool {
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        self.mac(payload).verify_slice(&signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let signer = Signer::new(Some("secret"));
+        let signature = signer.sign("/private|100");
+
+        assert!(signer.verify("/private|100", &signature));
+        assert!(!signer.verify("/private|101", &signature));
+        assert!(!Signer::new(None).verify("/private|100", &signature));
+        assert!(!signer.verify("/private|100", "not base64!"));
+    }
+}
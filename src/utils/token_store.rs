@@ -0,0 +1,115 @@
+//! Refresh tokens kept apart from `config.toml`
+//!
+//! OneDrive rotates the refresh token on every refresh, so the store is
+//! rewritten each time and `config.toml` can stay read-only.
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use arc_swap::ArcSwap;
+use snafu::ResultExt;
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    error::{Error, ParseTokensSnafu, ReadTokensSnafu, WriteTokensSnafu},
+    onedrive::Onedrive,
+};
+
+/// Refresh tokens keyed by mount name
+pub type Tokens = BTreeMap<String, String>;
+
+#[derive(Debug)]
+pub struct TokenStore {
+    path: PathBuf,
+    /// Serializes the writers, so their temp files don't clash and the
+    /// tokens read last are the ones written last
+    lock: Mutex<()>,
+}
+
+impl TokenStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Read the stored tokens, a missing store being empty
+    pub async fn load(&self) -> Result<Tokens, Error> {
+        match fs::read_to_string(&self.path).await {
+            Ok(content) => toml::from_str(&content).context(ParseTokensSnafu),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Tokens::new()),
+            Err(e) => Err(e).context(ReadTokensSnafu),
+        }
+    }
+
+    /// Write the current refresh token of every drive
+    pub async fn save<'a>(
+        &self,
+        drives: impl IntoIterator<Item = (&'a String, &'a ArcSwap<Onedrive>)>,
+    ) -> Result<(), Error> {
+        // Read under the lock, or a snapshot taken before another writer's
+        // rotation could overwrite the newer token it stored
+        let _guard = self.lock.lock().await;
+        let tokens: Tokens = drives
+            .into_iter()
+            .filter_map(|(mount, drive)| {
+                let refresh_token = drive.load().token.refresh_token.clone()?;
+                Some((mount.clone(), refresh_token))
+            })
+            .collect();
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        write_atomic(&self.path, toml::to_string(&tokens).unwrap().as_bytes())
+            .await
+            .context(WriteTokensSnafu)
+    }
+}
+
+/// Write to a temp file next to `path` and rename it over `path`, so a crash
+/// never leaves a truncated store behind
+pub async fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&temp).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&temp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path().join("tokens.toml"));
+        assert!(store.load().await.unwrap().is_empty());
+
+        let tokens = Tokens::from([
+            (String::new(), "root-token".to_string()),
+            ("music".to_string(), "music-token".to_string()),
+        ]);
+        write_atomic(&store.path, toml::to_string(&tokens).unwrap().as_bytes())
+            .await
+            .unwrap();
+
+        assert_eq!(store.load().await.unwrap(), tokens);
+        assert!(!dir.path().join("tokens.toml.tmp").exists());
+    }
+}
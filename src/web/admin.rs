@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use snafu::{ResultExt, Snafu};
+
+use crate::{model::FileTypes, worker, DRIVES};
+
+use super::{auth::now, share::Target, AppState};
+
+/// How long a share link lasts unless asked otherwise
+const SHARE_TTL: u64 = 60 * 60 * 24;
+
+async fn login_root(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    login(&state, &headers, "").await
+}
+
+async fn login_mount(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(mount): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    login(&state, &headers, &mount).await
+}
+
+async fn login(
+    state: &AppState,
+    headers: &HeaderMap,
+    mount: &str,
+) -> Result<impl IntoResponse, Error> {
+    authorize(state, headers)?;
+
+    let drive = DRIVES
+        .get()
+        .and_then(|drives| drives.get(mount))
+        .ok_or(Error::DriveNotFound {
+            mount: mount.to_string(),
+        })?;
+    let prompt = worker::login(mount, drive).await.context(LoginSnafu)?;
+
+    Ok(Json(json!({ "prompt": prompt })))
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareRequest {
+    path: Option<String>,
+    id: Option<String>,
+    /// Seconds the link stays valid
+    expires_in: Option<u64>,
+    max_downloads: Option<u32>,
+}
+
+/// Mint a share link to a single file
+async fn share(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<ShareRequest>,
+) -> Result<impl IntoResponse, Error> {
+    authorize(&state, &headers)?;
+
+    let (target, item) = match (request.path, request.id) {
+        (Some(path), None) => {
+            let item = state.backend.get_item(&path, &state.cache).await;
+            (Target::Path(path), item)
+        }
+        (None, Some(id)) => {
+            let item = state.backend.get_item_by_id(&id, &state.cache).await;
+            (Target::Id(id), item)
+        }
+        _ => return Err(Error::InvalidShare),
+    };
+    let item = item.context(GetItemSnafu)?;
+    if matches!(item.file_type, FileTypes::Folder) || state.hide.is_hidden(&item.full_path) {
+        return Err(Error::NotShareable {
+            path: item.full_path,
+        });
+    }
+
+    let expires = now()
+        .checked_add(request.expires_in.unwrap_or(SHARE_TTL))
+        .ok_or(Error::InvalidExpiry)?;
+    let token = state.shares.mint(&target, expires, request.max_downloads);
+
+    Ok(Json(
+        json!({ "url": format!("/s/{}", token), "expires": expires }),
+    ))
+}
+
+/// Check the `Authorization: Bearer` header against the admin token
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), Error> {
+    let token = state.admin_token.as_deref().ok_or(Error::Disabled)?;
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+enum Error {
+    #[snafu(display("The admin endpoints are disabled, set admin_token to enable them"))]
+    Disabled,
+
+    #[snafu(display("Invalid admin token"))]
+    Unauthorized,
+
+    #[snafu(display("Drive not found: {}", mount))]
+    DriveNotFound { mount: String },
+
+    #[snafu(display("Failed to start the login: {}", source))]
+    Login { source: crate::onedrive::Error },
+
+    #[snafu(display("Give either the path or the id of the file to share"))]
+    InvalidShare,
+
+    #[snafu(display("The share link would expire too far in the future"))]
+    InvalidExpiry,
+
+    #[snafu(display("Only files can be shared: {}", path))]
+    NotShareable { path: String },
+
+    #[snafu(display("Failed to get the item: {}", source))]
+    GetItem { source: crate::storage::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        let status = match self {
+            Error::Disabled | Error::DriveNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::InvalidShare | Error::InvalidExpiry | Error::NotShareable { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::Login { .. } | Error::GetItem { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> axum::Router {
+    let route = axum::Router::new()
+        .route("/login", post(login_root))
+        .route("/login/{mount}", post(login_mount))
+        .route("/share", post(share))
+        .with_state(state);
+
+    axum::Router::new().nest("/admin", route)
+}
@@ -0,0 +1,301 @@
+//! Access to hidden items and password-protected folders
+//!
+//! Unlocking a folder hands out a token signed for that folder's path, kept
+//! in a cookie so downloads and thumbnails carry it as well.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use serde_json::json;
+use snafu::{ResultExt, Snafu};
+use tracing::warn;
+
+use crate::utils::{config::ProtectSetting, password::verify_password, sign::Signer};
+
+use super::AppState;
+
+/// How long an unlocked folder stays unlocked
+const TOKEN_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+const COOKIE_PREFIX: &str = "onelist_auth_";
+
+#[derive(Debug)]
+struct Rule {
+    path: String,
+    password: String,
+}
+
+#[derive(Debug)]
+pub struct Protection {
+    rules: Vec<Rule>,
+    signer: Signer,
+}
+
+impl Protection {
+    pub fn new(rules: &[ProtectSetting], signer: Signer) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| Rule {
+                path: normalize(&rule.path),
+                password: rule.password.clone(),
+            })
+            .collect();
+
+        Self { rules, signer }
+    }
+
+    /// The innermost rule covering `path`
+    fn rule(&self, path: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| is_under(path, &rule.path))
+            .max_by_key(|rule| rule.path.len())
+    }
+
+    /// Check that the request carries a token unlocking `path`
+    pub fn check(&self, path: &str, headers: &HeaderMap) -> Result<(), Error> {
+        let Some(rule) = self.rule(&normalize(path)) else {
+            return Ok(());
+        };
+
+        if tokens(headers).any(|token| self.verify(token, &rule.path)) {
+            Ok(())
+        } else {
+            Err(Error::Locked {
+                path: rule.path.clone(),
+            })
+        }
+    }
+
+    /// Issue a token for the folder `path`, returning it with its expiry
+    fn issue(&self, path: &str) -> (String, u64) {
+        let expires = now() + TOKEN_TTL.as_secs();
+        let signature = self.signer.sign(&format!("{}|{}", path, expires));
+        (format!("{}.{}", expires, signature), expires)
+    }
+
+    fn verify(&self, token: &str, path: &str) -> bool {
+        let Some((expires, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let Ok(expires_at) = expires.parse::<u64>() else {
+            return false;
+        };
+
+        expires_at > now()
+            && self
+                .signer
+                .verify(&format!("{}|{}", path, expires), signature)
+    }
+}
+
+/// Check that `path` is neither hidden nor locked
+pub fn check_path(state: &AppState, path: &str, headers: &HeaderMap) -> Result<(), Error> {
+    if state.hide.is_hidden(path) {
+        return Err(Error::Hidden {
+            path: path.to_string(),
+        });
+    }
+
+    state.protection.check(path, headers)
+}
+
+/// Check access to the item `id`, looking up where it lives only when some
+/// folder is hidden or protected
+pub async fn check_id(state: &AppState, id: &str, headers: &HeaderMap) -> Result<(), Error> {
+    if state.protection.rules.is_empty() && state.hide.is_empty() {
+        return Ok(());
+    }
+
+    let item = state
+        .backend
+        .get_item_by_id(id, &state.cache)
+        .await
+        .context(GetItemSnafu)?;
+    check_path(state, &item.full_path, headers)
+}
+
+/// Normalize `path` to `/a/b`, `/` being the root
+pub(super) fn normalize(path: &str) -> String {
+    let path = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(String::new(), |path, segment| path + "/" + segment);
+    if path.is_empty() {
+        "/".to_string()
+    } else {
+        path
+    }
+}
+
+/// Whether `path` is `folder` or below it, ignoring case as OneDrive does
+/// when resolving paths
+pub(super) fn is_under(path: &str, folder: &str) -> bool {
+    folder == "/"
+        || path
+            .to_lowercase()
+            .strip_prefix(&folder.to_lowercase())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The tokens sent in the auth cookies or an `Authorization: Bearer` header
+fn tokens(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    let cookies = headers
+        .get_all(header::COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .filter(|(name, _)| name.starts_with(COOKIE_PREFIX))
+        .map(|(_, token)| token);
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    cookies.chain(bearer)
+}
+
+#[derive(Debug, Deserialize)]
+struct UnlockRequest {
+    path: String,
+    password: String,
+}
+
+async fn unlock(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<UnlockRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let protection = &state.protection;
+    let rule = protection
+        .rule(&normalize(&request.path))
+        .ok_or(Error::NotProtected {
+            path: request.path.clone(),
+        })?;
+
+    // Hashing is slow on purpose, keep it off the async workers
+    let hash = rule.password.clone();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&request.password, &hash))
+        .await
+        .unwrap_or_default();
+    match verified {
+        Some(true) => {}
+        Some(false) => return Err(Error::WrongPassword),
+        None => {
+            warn!("The password of '{}' is not an argon2 hash", rule.path);
+            return Err(Error::InvalidHash {
+                path: rule.path.clone(),
+            });
+        }
+    }
+
+    let (token, expires) = protection.issue(&rule.path);
+    let cookie = format!(
+        "{}{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        COOKIE_PREFIX,
+        URL_SAFE_NO_PAD.encode(&rule.path),
+        token,
+        TOKEN_TTL.as_secs()
+    );
+
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Json(json!({ "path": rule.path, "token": token, "expires": expires })),
+    ))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("{} is protected by a password", path))]
+    Locked { path: String },
+
+    #[snafu(display("{} is not protected", path))]
+    NotProtected { path: String },
+
+    #[snafu(display("Wrong password"))]
+    WrongPassword,
+
+    #[snafu(display("The password of {} is misconfigured", path))]
+    InvalidHash { path: String },
+
+    #[snafu(display("Item not found: {}", path))]
+    Hidden { path: String },
+
+    #[snafu(display("Failed to get the item: {}", source))]
+    GetItem { source: crate::storage::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        let status = match self {
+            Error::Locked { .. } | Error::WrongPassword => StatusCode::UNAUTHORIZED,
+            Error::NotProtected { .. } => StatusCode::BAD_REQUEST,
+            Error::Hidden { .. } => StatusCode::NOT_FOUND,
+            Error::InvalidHash { .. } | Error::GetItem { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let body = match &self {
+            Error::Locked { path } => json!({ "error": self.to_string(), "protected": path }),
+            _ => json!({ "error": self.to_string() }),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> axum::Router {
+    axum::Router::new()
+        .route("/auth", post(unlock))
+        .with_state(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let rules = [ProtectSetting {
+            path: "/private/".to_string(),
+            password: String::new(),
+        }];
+        let protection = Protection::new(&rules, Signer::new(Some("secret")));
+        let mut headers = HeaderMap::new();
+
+        assert!(protection.check("/", &headers).is_ok());
+        assert!(protection.check("/private-not", &headers).is_ok());
+        assert!(protection.check("/private", &headers).is_err());
+        assert!(protection.check("/private/a/b.txt", &headers).is_err());
+        assert!(protection.check("/PRIVATE/a", &headers).is_err());
+
+        let (token, _) = protection.issue("/private");
+        let cookie = format!("theme=dark; {}x={}", COOKIE_PREFIX, token);
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert!(protection.check("/private/a/b.txt", &headers).is_ok());
+
+        // A token for another folder doesn't unlock this one
+        let (token, _) = protection.issue("/other");
+        let cookie = format!("{}x={}", COOKIE_PREFIX, token);
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert!(protection.check("/private", &headers).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Serving proxied downloads from the content cache, and filling it
+
+use std::{ops::Range, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::Response,
+};
+use futures_util::{stream, StreamExt};
+
+use crate::storage::content::{ContentCache, Writer};
+
+use super::AppState;
+
+/// The response headers kept along with the content
+const STORED_HEADERS: [HeaderName; 4] = [
+    header::CONTENT_DISPOSITION,
+    header::CONTENT_TYPE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+/// The cache key of the content of `id`, when it can be answered from or
+/// stored in the cache
+pub async fn key(
+    state: &AppState,
+    id: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Option<String> {
+    state.content.as_ref()?;
+    // Conditional requests are left to the upstream, which knows better
+    let conditional = [
+        header::IF_MATCH,
+        header::IF_NONE_MATCH,
+        header::IF_MODIFIED_SINCE,
+        header::IF_UNMODIFIED_SINCE,
+        header::IF_RANGE,
+    ]
+    .iter()
+    .any(|name| headers.contains_key(name));
+    if method != Method::GET || conditional {
+        return None;
+    }
+
+    let item = state.backend.get_item_by_id(id, &state.cache).await.ok()?;
+    Some(ContentCache::key(id, item.etag.as_deref()?))
+}
+
+/// Answer from the cache, when all of the requested range is on disk
+pub async fn serve(cache: &ContentCache, key: &str, headers: &HeaderMap) -> Option<Response> {
+    let stored = cache.lookup(key)?;
+    let range = match headers.get(header::RANGE) {
+        Some(range) => Some(parse_range(range.to_str().ok()?, stored.size)?),
+        None => None,
+    };
+    let content = range.clone().unwrap_or(0..stored.size);
+    let chunks = cache.read(key, content.clone()).await?;
+
+    let mut response = Response::new(Body::from_stream(chunks));
+    for (name, value) in &stored.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(content.end - content.start),
+    );
+    if range.is_some() {
+        let content_range = format!(
+            "bytes {}-{}/{}",
+            content.start,
+            content.end - 1,
+            stored.size
+        );
+        headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+
+    Some(response)
+}
+
+/// Store the content of `response` in the cache as it's streamed
+pub async fn tee(cache: &Arc<ContentCache>, key: &str, id: &str, response: Response) -> Response {
+    let headers = response.headers();
+    let (offset, size) = match response.status() {
+        StatusCode::OK => (0, content_length(headers)),
+        StatusCode::PARTIAL_CONTENT => match content_range(headers) {
+            Some((offset, size)) => (offset, Some(size)),
+            None => return response,
+        },
+        _ => return response,
+    };
+    let Some(size) = size else {
+        return response;
+    };
+
+    let stored = STORED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let Some(writer) = cache.writer(key, id, size, stored, offset).await else {
+        return response;
+    };
+
+    response.map(|body| {
+        let chunks = body.into_data_stream();
+        let chunks = stream::unfold(
+            (chunks, Some(writer)),
+            |(mut chunks, mut writer): (_, Option<Writer>)| async move {
+                match chunks.next().await {
+                    Some(Ok(data)) => {
+                        // A failed write only stops the caching
+                        if let Some(to) = &mut writer {
+                            if to.write(&data).await.is_err() {
+                                writer = None;
+                            }
+                        }
+                        Some((Ok(data), (chunks, writer)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (chunks, None))),
+                    None => {
+                        if let Some(writer) = writer {
+                            writer.finish().await;
+                        }
+                        None
+                    }
+                }
+            },
+        );
+        Body::from_stream(chunks)
+    })
+}
+
+/// The bytes of a file of `size` asked for by a `Range` header, when it's a
+/// single satisfiable range
+fn parse_range(value: &str, size: u64) -> Option<Range<u64>> {
+    let spec = value.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        size.saturating_sub(suffix)..size
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => size,
+            end => end.parse::<u64>().ok()?.saturating_add(1).min(size),
+        };
+        start..end
+    };
+
+    (range.start < range.end).then_some(range)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// The offset and total size from a `Content-Range` header
+fn content_range(headers: &HeaderMap) -> Option<(u64, u64)> {
+    let value = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (range, size) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.parse().ok()?, size.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-1", 5), Some(0..2));
+        assert_eq!(parse_range("bytes=2-", 5), Some(2..5));
+        assert_eq!(parse_range("bytes=1-99", 5), Some(1..5));
+        assert_eq!(parse_range("bytes=-2", 5), Some(3..5));
+        assert_eq!(parse_range("bytes=5-", 5), None);
+        assert_eq!(parse_range("bytes=0-1,3-4", 5), None);
+        assert_eq!(parse_range("items=0-1", 5), None);
+    }
+}
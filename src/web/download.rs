@@ -1,125 +1,551 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc, time::Duration};
 
 use axum::{
+    body::Body,
     extract::{Path, Request, State},
-    http::{StatusCode, Uri},
-    response::{IntoResponse, Redirect},
+    http::{header, HeaderMap, HeaderName, Method, StatusCode, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::get,
     Json,
 };
 
-use onedrive_api::{ItemId, ItemLocation};
-
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use serde_json::json;
-use snafu::Snafu;
+use snafu::{ResultExt, Snafu};
+use tower_http::{services::ServeFile, timeout::TimeoutBody};
+use tracing::debug;
+
+use crate::{
+    model::{FileInfo, FileTypes},
+    storage::Download,
+};
 
-use crate::DRIVE;
+use super::{
+    auth, content, list,
+    share::{Redeemed, Target, Use},
+    AppState, REQUEST_TIMEOUT,
+};
 
-use super::AppState;
+/// How long the upstream may take to start answering
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a proxied body may stall before the transfer is dropped
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The request headers a proxied download passes on
+pub(super) const FORWARDED_HEADERS: [HeaderName; 6] = [
+    header::RANGE,
+    header::IF_RANGE,
+    header::IF_MATCH,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_UNMODIFIED_SINCE,
+];
+
+/// The response headers a proxied download passes back
+const RETURNED_HEADERS: [HeaderName; 9] = [
+    header::ACCEPT_RANGES,
+    header::CACHE_CONTROL,
+    header::CONTENT_DISPOSITION,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::RETRY_AFTER,
+];
 
 async fn download_file(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> impl IntoResponse {
-    let drive = match DRIVE.get() {
-        Some(drive) => drive,
-        None => return Error::StillStarting.into_response(),
+    req: Request,
+) -> Response {
+    let target = bounded(download_target(&state, id, req.headers())).await;
+    deliver(&state, target, req).await
+}
+
+async fn download_path(State(state): State<Arc<AppState>>, req: Request) -> Response {
+    match bounded(path_target(&state, req.uri(), req.headers())).await {
+        Ok(resolved) => deliver(&state, Ok(resolved), req).await,
+        Err(response) => response,
+    }
+}
+
+async fn download_shared(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+    req: Request,
+) -> Response {
+    // The ranges of seeks and resumes go on with a download, unless no
+    // download was counted for the client yet
+    let usage = Use {
+        client: state.throttle.client_ip(&req),
+        download: req.method() == Method::GET,
+        resumed: req
+            .headers()
+            .get(header::RANGE)
+            .is_some_and(|range| !range.as_bytes().starts_with(b"bytes=0-")),
+    };
+    let Redeemed { target, counted } = match state.shares.redeem(&token, usage) {
+        Ok(redeemed) => redeemed,
+        Err(e) => return Error::Share { source: e }.into_response(),
     };
 
-    let cache = state.cache.download_url_cache.clone();
-    let cached_url = cache.get(&id);
-    let url = if let Some(url) = cached_url {
-        url
+    let target = bounded(shared_target(&state, target)).await;
+    let response = deliver(&state, target, req).await;
+    // A download that failed doesn't use up the link
+    let status = response.status();
+    if let Some(counted) = counted {
+        if !status.is_success() && !status.is_redirection() {
+            state.shares.refund(&counted);
+        }
+    }
+    response
+}
+
+/// Look up a download within the time other requests get, the download
+/// routes going without the timeout layer for the sake of proxied bodies
+async fn bounded<T, E: From<Error>>(lookup: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    tokio::time::timeout(REQUEST_TIMEOUT, lookup)
+        .await
+        .unwrap_or_else(|_| Err(Error::LookupTimeout.into()))
+}
+
+/// Proxy or redirect the download, as the proxy policy has it
+async fn deliver(state: &AppState, target: Result<Resolved, Error>, req: Request) -> Response {
+    let resolved = match target {
+        Ok(resolved) => resolved,
+        Err(e) => return e.into_response(),
+    };
+
+    let client = state.throttle.client_ip(&req);
+    let item = if state.policy.needs_item() {
+        bounded(async {
+            state
+                .backend
+                .get_item_by_id(&resolved.id, &state.cache)
+                .await
+                .context(GetItemSnafu)
+        })
+        .await
+        .ok()
     } else {
-        let url = drive
-            .load()
-            .drive
-            .get_item_download_url(ItemLocation::from_id(&ItemId(id.clone())))
-            .await;
-
-        match url {
-            Ok(url) => {
-                cache.insert(id, url.clone());
-                url
+        None
+    };
+
+    if state.policy.proxy(item.as_ref(), client) {
+        proxy(state, Ok(resolved), req).await
+    } else {
+        redirect(Ok(resolved), req).await
+    }
+}
+
+/// Redirect to the download URL, local files being served directly
+async fn redirect(target: Result<Resolved, Error>, req: Request) -> Response {
+    match target.map(|resolved| resolved.download) {
+        Ok(Download::Url(url)) => Redirect::to(&url).into_response(),
+        Ok(Download::File(path)) => serve_file(&path, req).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Stream the content of the download URL through the server
+async fn proxy(state: &AppState, target: Result<Resolved, Error>, req: Request) -> Response {
+    let Resolved {
+        id,
+        download,
+        cached,
+    } = match target {
+        Ok(resolved) => resolved,
+        Err(e) => return e.into_response(),
+    };
+    let permit = match state.throttle.admit(&req) {
+        Ok(permit) => permit,
+        Err(e) => return Error::Throttled { source: e }.into_response(),
+    };
+    let url = match download {
+        Download::Url(url) => url,
+        Download::File(path) => return serve_file(&path, req).await.map(|body| permit.limit(body)),
+    };
+
+    let (method, headers) = (req.method().clone(), req.headers().clone());
+    let content = match (
+        &state.content,
+        content::key(state, &id, &method, &headers).await,
+    ) {
+        (Some(cache), Some(key)) => Some((cache, key)),
+        _ => None,
+    };
+    if let Some((cache, key)) = &content {
+        if let Some(response) = content::serve(cache, key, &headers).await {
+            return response.map(|body| permit.limit(body));
+        }
+    }
+
+    let response = match fetch(state, &url, &method, &headers).await {
+        // A cached URL refused this way has most likely expired, and the item
+        // gets a fresh one
+        Ok(response) if cached && is_expired(response.status()) => {
+            debug!("Download url of '{}' refused, fetching a new one", id);
+            state.cache.download_url_cache.invalidate(&id);
+            match resolve_download(state, id.clone()).await {
+                Ok(Resolved {
+                    download: Download::Url(url),
+                    ..
+                }) => fetch(state, &url, &method, &headers).await,
+                Ok(Resolved {
+                    download: Download::File(path),
+                    ..
+                }) => return serve_file(&path, req).await.map(|body| permit.limit(body)),
+                Err(e) => Err(e),
             }
-            Err(e) => return Error::GetDownloadUrl { source: e }.into_response(),
         }
+        response => response,
     };
 
-    Redirect::to(&url).into_response()
+    let response = match (response, content) {
+        (Ok(response), Some((cache, key))) => {
+            content::tee(cache, &key, &id, downstream_response(response)).await
+        }
+        (Ok(response), None) => downstream_response(response),
+        (Err(e), _) => return e.into_response(),
+    };
+    response.map(|body| permit.limit(body))
 }
 
-async fn proxy_download_file(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<String>,
-    mut req: Request,
-) -> impl IntoResponse {
-    let drive = match DRIVE.get() {
-        Some(drive) => drive,
-        None => return Error::StillStarting.into_response(),
-    };
+/// Request `url` on behalf of a request with `method` and `headers`
+async fn fetch(
+    state: &AppState,
+    url: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    let upstream = upstream_request(url, method, headers)?;
+    match tokio::time::timeout(UPSTREAM_TIMEOUT, state.client.request(upstream)).await {
+        Ok(response) => Ok(response.context(ProxySnafu)?.map(Body::new)),
+        Err(_) => Err(Error::UpstreamTimeout),
+    }
+}
 
-    let cache = state.cache.download_url_cache.clone();
-    let cached_url = cache.get(&id);
-    let url = if let Some(url) = cached_url {
-        url
-    } else {
-        let url = drive
-            .load()
-            .drive
-            .get_item_download_url(ItemLocation::from_id(&ItemId(id.clone())))
-            .await;
-
-        match url {
-            Ok(url) => {
-                cache.insert(id, url.clone());
-                url
+/// Whether the upstream refused a download URL, which pre-authenticated URLs
+/// do once they expire
+fn is_expired(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+    )
+}
+
+/// The request for `url`, carrying over only what ranges and conditional
+/// requests need; cookies and the like are none of the upstream's business
+fn upstream_request(url: &str, method: &Method, headers: &HeaderMap) -> Result<Request, Error> {
+    let mut upstream = Request::builder().method(method).uri(url);
+    for name in FORWARDED_HEADERS {
+        for value in headers.get_all(&name) {
+            upstream = upstream.header(&name, value);
+        }
+    }
+
+    upstream.body(Body::empty()).context(UpstreamRequestSnafu)
+}
+
+/// Pass on the successful, partial and conditional answers of the upstream,
+/// its failures being ours to explain
+fn downstream_response(response: Response) -> Response {
+    let (parts, body) = response.into_parts();
+    match parts.status {
+        status if status.is_success() => {}
+        StatusCode::NOT_MODIFIED
+        | StatusCode::PRECONDITION_FAILED
+        | StatusCode::RANGE_NOT_SATISFIABLE => {}
+        status => return Error::Upstream { status }.into_response(),
+    }
+
+    let mut response = Response::new(Body::new(TimeoutBody::new(STALL_TIMEOUT, body)));
+    *response.status_mut() = parts.status;
+    for name in RETURNED_HEADERS {
+        for value in parts.headers.get_all(&name) {
+            response.headers_mut().append(&name, value.clone());
+        }
+    }
+    response
+}
+
+/// Where to download an item from
+struct Resolved {
+    id: String,
+    download: Download,
+    /// Whether the URL came from the cache, and may have expired since
+    cached: bool,
+}
+
+/// Look up where to download `id` from, caching download URLs
+async fn download_target(
+    state: &AppState,
+    id: String,
+    headers: &HeaderMap,
+) -> Result<Resolved, Error> {
+    auth::check_id(state, &id, headers)
+        .await
+        .context(DeniedSnafu)?;
+
+    resolve_download(state, id).await
+}
+
+async fn resolve_download(state: &AppState, id: String) -> Result<Resolved, Error> {
+    if let Some(url) = state.cache.download_url(&id) {
+        return Ok(Resolved {
+            id,
+            download: Download::Url(url),
+            cached: true,
+        });
+    }
+
+    let download = state
+        .backend
+        .get_download(&id, &state.cache)
+        .await
+        .context(GetDownloadUrlSnafu)?;
+    if let Download::Url(url) = &download {
+        state.cache.insert_download_url(id.clone(), url.clone());
+    }
+
+    Ok(Resolved {
+        id,
+        download,
+        cached: false,
+    })
+}
+
+/// Resolve the target of a share link, which grants access on its own
+async fn shared_target(state: &AppState, target: Target) -> Result<Resolved, Error> {
+    let id = match target {
+        Target::Id(id) => id,
+        Target::Path(path) if state.hide.is_hidden(&path) => {
+            return Err(Error::Denied {
+                source: auth::Error::Hidden { path },
+            })
+        }
+        Target::Path(path) => {
+            let item = state
+                .backend
+                .get_item(&path, &state.cache)
+                .await
+                .context(GetItemSnafu)?;
+            // Checked again on the path the backend resolved
+            if state.hide.is_hidden(&item.full_path) {
+                return Err(Error::Denied {
+                    source: auth::Error::Hidden {
+                        path: item.full_path,
+                    },
+                });
             }
-            Err(e) => return Error::GetDownloadUrl { source: e }.into_response(),
+            item.id
         }
     };
 
-    let client = &state.client;
+    resolve_download(state, id).await
+}
+
+/// Resolve `/d/{path}` to the file to download, folders being answered with
+/// an index of their children
+async fn path_target(
+    state: &Arc<AppState>,
+    uri: &Uri,
+    headers: &HeaderMap,
+) -> Result<Resolved, Response> {
+    let path = uri.path().strip_prefix("/d/").unwrap_or_default();
+    let path = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+    let full_path = format!("/{}", path);
+    auth::check_path(state, &full_path, headers)
+        .context(DeniedSnafu)
+        .map_err(IntoResponse::into_response)?;
+
+    if !path.trim_matches('/').is_empty() {
+        let item = state
+            .backend
+            .get_item(&full_path, &state.cache)
+            .await
+            .context(GetItemSnafu)
+            .map_err(IntoResponse::into_response)?;
+        // The path the backend resolved may differ from the one asked for
+        auth::check_path(state, &item.full_path, headers)
+            .context(DeniedSnafu)
+            .map_err(IntoResponse::into_response)?;
+        if !matches!(item.file_type, FileTypes::Folder) {
+            return resolve_download(state, item.id)
+                .await
+                .map_err(IntoResponse::into_response);
+        }
+    }
+
+    // Relative links in the index need the trailing slash
+    if !uri.path().ends_with('/') {
+        return Err(Redirect::permanent(&format!("{}/", uri.path())).into_response());
+    }
+
+    let children = list::list_inner(state.clone(), full_path.clone(), headers)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Err(Html(folder_index(&full_path, &children)).into_response())
+}
+
+/// A plain HTML index of `children`, enough for browsers and `wget -r`
+fn folder_index(path: &str, children: &[FileInfo]) -> String {
+    let title = escape_html(&format!("Index of {}", path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<ul>\n",
+        title, title
+    );
+    if path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for child in children {
+        let suffix = if matches!(child.file_type, FileTypes::Folder) {
+            "/"
+        } else {
+            ""
+        };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            utf8_percent_encode(&child.name, SEGMENT),
+            suffix,
+            escape_html(&child.name),
+            suffix
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
 
-    req.headers_mut().remove("host");
-    req.headers_mut().remove("referer");
-    *req.uri_mut() = Uri::try_from(url).unwrap();
+/// Characters escaped in a path segment
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
 
-    let ret = client.request(req).await;
-    match ret {
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Serve a file from the local disk, handling range and conditional requests
+async fn serve_file(path: &std::path::Path, req: Request) -> Response {
+    match ServeFile::new(path).try_call(req).await {
         Ok(response) => response.into_response(),
-        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+        Err(e) => Error::ServeFile { source: e }.into_response(),
     }
 }
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 enum Error {
-    #[snafu(display("Server still in the process of starting up"))]
-    StillStarting,
-
     #[snafu(display("Failed to get the download url: {}", source))]
-    GetDownloadUrl { source: onedrive_api::Error },
+    GetDownloadUrl { source: crate::storage::Error },
+
+    #[snafu(display("Failed to get the item: {}", source))]
+    GetItem { source: crate::storage::Error },
+
+    #[snafu(display("Failed to serve the file: {}", source))]
+    ServeFile { source: std::io::Error },
+
+    #[snafu(display("Invalid download url: {}", source))]
+    UpstreamRequest { source: axum::http::Error },
+
+    #[snafu(display("Failed to reach the download server: {}", source))]
+    Proxy {
+        source: hyper_util::client::legacy::Error,
+    },
+
+    #[snafu(display("The download server didn't answer in time"))]
+    UpstreamTimeout,
+
+    #[snafu(display("Timed out looking up the download"))]
+    LookupTimeout,
+
+    #[snafu(display("The download server answered {}", status))]
+    Upstream { status: StatusCode },
+
+    #[snafu(display("{}", source))]
+    Share { source: super::share::Error },
+
+    #[snafu(display("{}", source))]
+    Denied { source: super::auth::Error },
+
+    #[snafu(display("{}", source))]
+    Throttled { source: super::throttle::Error },
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": self.to_string() })),
-        )
-            .into_response()
+        match self {
+            Error::Denied { source } => return source.into_response(),
+            Error::Share { source } => return source.into_response(),
+            Error::Throttled { source } => return source.into_response(),
+            _ => {}
+        }
+
+        let status = match &self {
+            // Unknown and unpublished ids alike, without telling which
+            Error::GetDownloadUrl { source } | Error::GetItem { source }
+                if source.is_not_found() =>
+            {
+                return not_found();
+            }
+            Error::Upstream { status } => match *status {
+                StatusCode::NOT_FOUND | StatusCode::GONE => StatusCode::NOT_FOUND,
+                StatusCode::TOO_MANY_REQUESTS => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            Error::Proxy { .. } => StatusCode::BAD_GATEWAY,
+            Error::UpstreamTimeout | Error::LookupTimeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
     }
 }
 
-pub fn router(state: Arc<AppState>, use_proxy: bool) -> axum::Router {
-    let route = axum::Router::new();
-    let route = if use_proxy {
-        route.route("/{id}", get(proxy_download_file))
-    } else {
-        route.route("/{id}", get(download_file))
+pub(super) fn not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "error": "Item not found" })),
+    )
+        .into_response()
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        e.into_response()
     }
-    .with_state(state);
+}
+
+pub fn router(state: Arc<AppState>) -> axum::Router {
+    let route = axum::Router::new()
+        .route("/{id}", get(download_file))
+        .with_state(state);
 
     axum::Router::new().nest("/download", route)
 }
+
+/// Downloads addressed by path, `/d/{path}`, and share links, `/s/{token}`,
+/// outside of `/api`
+pub fn path_router(state: Arc<AppState>) -> axum::Router {
+    axum::Router::new()
+        .route("/d/", get(download_path))
+        .route("/d/{*path}", get(download_path))
+        .route("/s/{token}", get(download_shared))
+        .with_state(state)
+}
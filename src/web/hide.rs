@@ -0,0 +1,115 @@
+//! Items hidden from the listings and refused when requested directly
+
+use glob::{MatchOptions, Pattern};
+use regex::{Regex, RegexBuilder};
+
+use crate::utils::config::HideSetting;
+
+/// Case is ignored, as OneDrive resolves `/A.NFO` to `/a.nfo`
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug)]
+pub struct HideRules {
+    /// Globs on the full path
+    paths: Vec<Pattern>,
+    /// Globs on the name
+    names: Vec<Pattern>,
+    regexes: Vec<Regex>,
+}
+
+impl HideRules {
+    pub fn new(setting: &HideSetting) -> Self {
+        let mut paths = Vec::new();
+        let mut names = Vec::new();
+        for glob in &setting.glob {
+            let pattern = Pattern::new(glob)
+                .unwrap_or_else(|e| panic!("Invalid hide glob '{}': {}", glob, e));
+            if glob.contains('/') {
+                paths.push(pattern);
+            } else {
+                names.push(pattern);
+            }
+        }
+
+        let regexes = setting
+            .regex
+            .iter()
+            .map(|regex| {
+                RegexBuilder::new(regex)
+                    .case_insensitive(true)
+                    .build()
+                    .unwrap_or_else(|e| panic!("Invalid hide regex '{}': {}", regex, e))
+            })
+            .collect();
+
+        Self {
+            paths,
+            names,
+            regexes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.names.is_empty() && self.regexes.is_empty()
+    }
+
+    /// Whether `path` or any folder above it is hidden
+    pub fn is_hidden(&self, path: &str) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let mut current = String::new();
+        path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .any(|name| {
+                current = current.clone() + "/" + name;
+                self.matches(&current, name)
+            })
+    }
+
+    fn matches(&self, path: &str, name: &str) -> bool {
+        self.names
+            .iter()
+            .any(|pattern| pattern.matches_with(name, MATCH_OPTIONS))
+            || self
+                .paths
+                .iter()
+                .any(|pattern| pattern.matches_with(path, MATCH_OPTIONS))
+            || self.regexes.iter().any(|regex| regex.is_match(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_hidden() {
+        let rules = HideRules::new(&HideSetting {
+            glob: vec![
+                "*.nfo".to_string(),
+                ".*".to_string(),
+                "/private/**".to_string(),
+            ],
+            regex: vec!["^/Movies/.*\\.bak$".to_string()],
+        });
+
+        assert!(!rules.is_hidden("/"));
+        assert!(!rules.is_hidden("/Movies/a.mkv"));
+        assert!(rules.is_hidden("/Movies/a.nfo"));
+        assert!(rules.is_hidden("/Movies/A.NFO"));
+        assert!(rules.is_hidden("/PRIVATE/a"));
+        assert!(rules.is_hidden("/movies/a.mkv.BAK"));
+        assert!(rules.is_hidden("/.git"));
+        assert!(rules.is_hidden("/.git/config"));
+        assert!(!rules.is_hidden("/private"));
+        assert!(rules.is_hidden("/private/a/b.txt"));
+        assert!(rules.is_hidden("/Movies/a.mkv.bak"));
+        assert!(!rules.is_hidden("/Music/a.mkv.bak"));
+    }
+}
@@ -1,106 +1,211 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::HeaderMap,
     response::IntoResponse,
     routing::get,
     Json,
 };
-use onedrive_api::ItemLocation;
 
+use serde::Deserialize;
 use serde_json::json;
-use snafu::Snafu;
+use snafu::{ResultExt, Snafu};
 
 use crate::{
-    model::{item::parse_item, FileInfo},
-    DRIVE,
+    model::{Cached, FileInfo, FileTypes},
+    utils::natural::natural_cmp,
 };
 
-use super::AppState;
+use super::{auth, AppState};
+
+/// What to return of a folder: its children filtered by `type` and `q`,
+/// sorted, then sliced by `offset` and `limit`
+#[derive(Debug, Default, Deserialize)]
+struct ListQuery {
+    sort: Option<Sort>,
+    #[serde(default)]
+    order: Order,
+    #[serde(rename = "type")]
+    file_type: Option<TypeFilter>,
+    q: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
 
-async fn list(State(state): State<Arc<AppState>>, Path(p): Path<String>) -> impl IntoResponse {
-    let home_dir = &state.home_dir;
-    let p = format!("/{}", p);
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Sort {
+    Name,
+    Size,
+    Modified,
+}
 
-    let dir = format!("{}{}", home_dir, p);
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
 
-    let children = match list_inner(state, dir).await {
-        Ok(children) => children,
-        Err(e) => return e.into_response(),
-    };
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TypeFilter {
+    Video,
+    Audio,
+    Folder,
+    File,
+}
 
-    (
-        axum::http::StatusCode::OK,
-        Json(json!({ "files": *children })),
-    )
-        .into_response()
+impl TypeFilter {
+    fn matches(self, file_type: &FileTypes) -> bool {
+        matches!(
+            (self, file_type),
+            (TypeFilter::Video, FileTypes::Video)
+                | (TypeFilter::Audio, FileTypes::Audio)
+                | (TypeFilter::Folder, FileTypes::Folder)
+                | (TypeFilter::File, FileTypes::File)
+        )
+    }
 }
 
-async fn list_home(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let home_dir = state.home_dir.clone();
+async fn list(
+    State(state): State<Arc<AppState>>,
+    Path(p): Path<String>,
+    Query(query): Query<ListQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let dir = format!("/{}", p);
+
+    match list_inner(state, dir, &headers).await {
+        Ok(children) => respond(&children, &query).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn list_home(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    match list_inner(state, "/".to_string(), &headers).await {
+        Ok(children) => respond(&children, &query).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
 
-    let children = match list_inner(state, home_dir).await {
-        Ok(children) => children,
-        Err(e) => return e.into_response(),
-    };
+/// Answer with the requested slice of `children`, along with the offset of
+/// the next one while there is more to load
+fn respond(children: &[FileInfo], query: &ListQuery) -> impl IntoResponse {
+    let children = select(children, query);
+    let total = children.len();
+    let offset = query.offset.unwrap_or(0).min(total);
+    let end = query
+        .limit
+        .map_or(total, |limit| offset.saturating_add(limit).min(total));
+    let next_offset = (end < total).then_some(end);
 
     (
         axum::http::StatusCode::OK,
-        Json(json!({ "files": *children })),
+        Json(json!({
+            "files": &children[offset..end],
+            "total": total,
+            "next_offset": next_offset,
+        })),
     )
-        .into_response()
 }
 
-async fn list_inner(state: Arc<AppState>, dir: String) -> Result<Arc<Vec<FileInfo>>, Error> {
+/// Filter and sort the cached `children` for `query`, keeping Graph's order
+/// when no sort is asked for
+fn select<'a>(children: &'a [FileInfo], query: &ListQuery) -> Vec<&'a FileInfo> {
+    let q = query.q.as_deref().map(str::to_lowercase);
+    let mut children: Vec<_> = children
+        .iter()
+        .filter(|child| {
+            query
+                .file_type
+                .is_none_or(|file_type| file_type.matches(&child.file_type))
+        })
+        .filter(|child| {
+            q.as_deref()
+                .is_none_or(|q| child.name.to_lowercase().contains(q))
+        })
+        .collect();
+
+    if let Some(sort) = query.sort {
+        children.sort_by(|a, b| {
+            let ordering = match sort {
+                Sort::Name => natural_cmp(&a.name, &b.name),
+                Sort::Size => a.size.cmp(&b.size),
+                Sort::Modified => a.last_modified_date_time.cmp(&b.last_modified_date_time),
+            };
+            let ordering = ordering.then_with(|| natural_cmp(&a.name, &b.name));
+            match query.order {
+                Order::Asc => ordering,
+                Order::Desc => ordering.reverse(),
+            }
+        });
+    }
+
+    children
+}
+
+pub(super) async fn list_inner(
+    state: Arc<AppState>,
+    dir: String,
+    headers: &HeaderMap,
+) -> Result<Arc<Vec<FileInfo>>, Error> {
+    auth::check_path(&state, &dir, headers).context(DeniedSnafu)?;
+
     let list_cache = &state.cache.list_cache;
     if let Some(cached) = list_cache.get(&dir) {
-        return Ok(cached);
+        return Ok(cached.value().clone());
     }
 
-    let item_location = ItemLocation::from_path(&dir).ok_or(Error::LocationNotFound {
-        location: dir.to_string(),
-    });
-    let item_location = match item_location {
-        Ok(item_location) => item_location,
-        Err(e) => return Err(e),
-    };
-
-    let drive = match DRIVE.get() {
-        Some(drive) => drive,
-        None => return Err(Error::StillStarting),
-    };
-    let children = drive.load().drive.list_children(item_location).await;
-
-    match children {
-        Ok(children) => {
-            let children: Vec<_> = children
-                .iter()
-                .filter_map(|item| parse_item(item, &state.cache, &state.home_dir).ok())
-                .collect();
-            let children = Arc::new(children);
-            list_cache.insert(dir.to_string(), children.clone());
-
-            Ok(children)
-        }
-        Err(e) => Err(Error::ListChildren { source: e }),
+    let children = state
+        .backend
+        .list_children(&dir, &state.cache)
+        .await
+        .context(ListChildrenSnafu)?;
+
+    // The children know the path of the folder as the backend resolved it,
+    // which may differ from the one asked for
+    if let Some((parent, _)) = children
+        .first()
+        .and_then(|child| child.full_path.rsplit_once('/'))
+    {
+        let parent = if parent.is_empty() { "/" } else { parent };
+        auth::check_path(&state, parent, headers).context(DeniedSnafu)?;
     }
+
+    let children: Vec<_> = children
+        .into_iter()
+        .filter(|child| !state.hide.is_hidden(&child.full_path))
+        .collect();
+
+    let children = Arc::new(children);
+    list_cache.insert(dir, Cached::new(children.clone()));
+
+    Ok(children)
 }
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
-enum Error {
-    #[snafu(display("Server still in the process of starting up"))]
-    StillStarting,
-
-    #[snafu(display("Location not found: {}", location))]
-    LocationNotFound { location: String },
-
+pub(super) enum Error {
     #[snafu(display("Failed to list the children: {}", source))]
-    ListChildren { source: onedrive_api::Error },
+    ListChildren { source: crate::storage::Error },
+
+    #[snafu(display("{}", source))]
+    Denied { source: super::auth::Error },
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        if let Error::Denied { source } = self {
+            return source.into_response();
+        }
+
         (
             axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": self.to_string() })),
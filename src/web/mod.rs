@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    net::SocketAddr,
     sync::{Arc, OnceLock},
     time::Duration,
 };
@@ -13,23 +14,67 @@ use axum::{
 };
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use mini_moka::sync::Cache;
 use rust_embed::RustEmbed;
 use tokio::signal;
 use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
-use tracing::info;
+use tracing::{error, info, warn};
 
-use crate::{model::Caches, utils::config::Setting, NAME};
+use crate::{
+    model::{
+        snapshot::{self, CacheSnapshot},
+        Caches,
+    },
+    storage::{self, content::ContentCache, index::SearchIndex, StorageBackend},
+    utils::{config::Setting, sign::Signer},
+    worker::{self, delta::Watched},
+    NAME,
+};
 
+mod admin;
+mod auth;
+mod content;
 mod download;
+mod hide;
 mod item;
 mod list;
+mod policy;
+mod search;
+mod share;
+mod status;
+mod throttle;
 mod thumb;
 
 type Client = hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Body>;
 
 pub async fn web_server(config: Setting) {
-    let app = router(config.clone());
+    let caches = Arc::new(build_caches(&config));
+
+    // Restored before the delta workers start from the saved positions
+    let snapshot = config
+        .setting
+        .cache
+        .snapshot
+        .as_ref()
+        .map(|path| Arc::new(CacheSnapshot::new(path, &config)));
+    if let Some(snapshot) = &snapshot {
+        match snapshot.load(&caches).await {
+            Ok(true) => info!("Caches restored from the snapshot"),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to load the cache snapshot: {:?}", e),
+        }
+        let interval = config
+            .setting
+            .cache
+            .snapshot_interval
+            .unwrap_or(SNAPSHOT_INTERVAL);
+        worker::snapshot::spawn(
+            snapshot.clone(),
+            caches.clone(),
+            Duration::from_secs(interval),
+        );
+    }
+
+    let app = router(config.clone(), caches.clone());
 
     info!("Starting the web server");
 
@@ -37,30 +82,104 @@ pub async fn web_server(config: Setting) {
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.setting.port))
         .await
         .unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+
+    if let Some(snapshot) = snapshot {
+        match snapshot.save(&caches).await {
+            Ok(_) => info!("Cache snapshot saved"),
+            Err(e) => error!("Failed to save the cache snapshot: {:?}", e),
+        }
+    }
 
     info!("Web server stopped");
 }
 
 #[derive(Debug)]
 struct AppState {
-    home_dir: String,
-    cache: Caches,
+    backend: Arc<dyn StorageBackend>,
+    cache: Arc<Caches>,
     client: Client,
+    protection: auth::Protection,
+    hide: hide::HideRules,
+    shares: share::ShareLinks,
+    admin_token: Option<String>,
+    /// The local search index, when enabled
+    index: Option<Arc<SearchIndex>>,
+    throttle: throttle::Throttle,
+    policy: policy::ProxyPolicy,
+    /// The cache of proxied content, when enabled
+    content: Option<Arc<ContentCache>>,
 }
 
+/// How long a request may take to answer, apart from proxied downloads
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
 const CACHE_DURATION: Duration = Duration::from_secs(60 * 10);
+/// How long folder contents stay cached when the delta workers invalidate
+/// them as they change
+const WATCHED_CACHE_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+/// The most bytes of proxied content cached on disk when not configured
+const CONTENT_CACHE_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+/// Seconds between two cache snapshots when not configured
+const SNAPSHOT_INTERVAL: u64 = 60 * 10;
 
-fn router(config: Setting) -> Router {
-    let home_dir = if config.setting.home_dir.starts_with('/') {
-        config.setting.home_dir
+fn build_caches(config: &Setting) -> Caches {
+    // Local folders have no delta to follow, keep the short TTL with them
+    let drives = config.drives();
+    let listing_ttl = if drives.iter().all(|drive| drive.local.is_none()) {
+        WATCHED_CACHE_DURATION
     } else {
-        format!("/{}", config.setting.home_dir)
+        CACHE_DURATION
     };
 
+    Caches::with_setting(&config.setting.cache, CACHE_DURATION, listing_ttl)
+}
+
+fn router(config: Setting, caches: Arc<Caches>) -> Router {
+    let drives = config.drives();
+    let backend = storage::build(&drives);
+
+    let index = config.index.enable.then(|| {
+        Arc::new(SearchIndex::new(
+            &config.index.path,
+            snapshot::fingerprint(&config),
+        ))
+    });
+    if let Some(index) = &index {
+        // Local folders have no delta to follow, crawl them all again instead
+        let recrawl = drives
+            .iter()
+            .any(|drive| drive.local.is_some())
+            .then(|| Duration::from_secs(config.index.interval));
+        worker::index::spawn(backend.clone(), index.clone(), recrawl);
+    }
+
+    for drive in drives.iter().filter(|drive| drive.local.is_none()) {
+        worker::delta::spawn(
+            Watched::new(&drive.mount, &drive.home_dir, storage::is_mounted(&drives)),
+            caches.clone(),
+            index.clone(),
+        );
+    }
+
+    let status = status::router().layer(TimeoutLayer::new(REQUEST_TIMEOUT));
+    app(content_router(backend, caches, index, &config).nest("/api", status))
+}
+
+/// The routes serving the content of `backend`
+fn content_router(
+    backend: Arc<dyn StorageBackend>,
+    caches: Arc<Caches>,
+    index: Option<Arc<SearchIndex>>,
+    config: &Setting,
+) -> Router {
+    let signer = Signer::new(config.setting.secret.as_deref());
+
     let client = hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new()).build(
         HttpsConnectorBuilder::new()
             .with_webpki_roots()
@@ -69,32 +188,55 @@ fn router(config: Setting) -> Router {
             .build(),
     );
 
-    let download_url_cache = Cache::builder().time_to_live(CACHE_DURATION).build();
-    let list_cache = Cache::builder().time_to_live(CACHE_DURATION).build();
-    let thumb_cache = Cache::builder().time_to_live(CACHE_DURATION).build();
-    let file_cache = Cache::builder().time_to_live(CACHE_DURATION).build();
+    let content = config.setting.proxy.cache_dir.as_ref().map(|dir| {
+        let size = config
+            .setting
+            .proxy
+            .cache_size
+            .unwrap_or(CONTENT_CACHE_SIZE);
+        let cache = Arc::new(ContentCache::new(dir, size));
+        let loading = cache.clone();
+        tokio::spawn(async move {
+            if let Err(e) = loading.load().await {
+                warn!("Failed to load the content cache: {:?}", e);
+            }
+        });
+        cache
+    });
+
     let state = Arc::new(AppState {
-        home_dir,
-        cache: Caches {
-            download_url_cache,
-            list_cache,
-            thumb_cache,
-            file_cache,
-        },
+        backend,
+        cache: caches,
         client,
+        protection: auth::Protection::new(&config.protect, signer.clone()),
+        hide: hide::HideRules::new(&config.hide),
+        shares: share::ShareLinks::new(signer),
+        admin_token: config.setting.admin_token.clone(),
+        index,
+        throttle: throttle::Throttle::new(&config.setting.proxy),
+        policy: policy::ProxyPolicy::new(&config.proxy_rules, config.setting.use_proxy),
+        content,
     });
 
-    let router = Router::new()
+    let api = Router::new()
         .merge(list::router(state.clone()))
-        .merge(thumb::router(state.clone(), config.setting.use_proxy))
-        .merge(download::router(state.clone(), config.setting.use_proxy))
-        .merge(item::router(state.clone()));
+        .merge(thumb::router(state.clone()))
+        .merge(item::router(state.clone()))
+        .merge(search::router(state.clone()))
+        .merge(auth::router(state.clone()))
+        .merge(admin::router(state.clone()))
+        .layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+        .merge(download::router(state.clone()));
 
     Router::new()
-        .nest("/api", router)
+        .nest("/api", api)
+        .merge(download::path_router(state))
+}
+
+fn app(router: Router) -> Router {
+    router
         .fallback_service(get(static_handler))
         .layer(TraceLayer::new_for_http())
-        .layer(TimeoutLayer::new(Duration::from_secs(20)))
 }
 
 async fn shutdown_signal() {
@@ -168,3 +310,690 @@ async fn index_html() -> Response {
 async fn not_found() -> Response {
     (StatusCode::NOT_FOUND, "404").into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use axum::{body::to_bytes, http::Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::{
+        model::{FileInfo, FileTypes, Thumbnails},
+        storage::{Download, Error},
+        utils::password::hash_password,
+    };
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FakeBackend {
+        /// Where the download URLs point to
+        download_base: String,
+    }
+
+    impl Default for FakeBackend {
+        fn default() -> Self {
+            Self {
+                download_base: "https://example.com".to_string(),
+            }
+        }
+    }
+
+    fn file(name: &str) -> FileInfo {
+        FileInfo {
+            id: format!("id-{}", name),
+            name: name.to_string(),
+            size: 42,
+            last_modified_date_time: 0,
+            full_path: format!("/{}", name),
+            file_type: FileTypes::File,
+            etag: Some(format!("etag-{}", name)),
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for FakeBackend {
+        async fn list_children(&self, path: &str, _: &Caches) -> Result<Vec<FileInfo>, Error> {
+            match path {
+                "/" => Ok(vec![file("a.txt"), file("b.txt")]),
+                _ => Err(Error::LocationNotFound {
+                    location: path.to_string(),
+                }),
+            }
+        }
+
+        /// Paths resolve ignoring case, as on OneDrive
+        async fn get_item(&self, path: &str, _: &Caches) -> Result<FileInfo, Error> {
+            Ok(file(&path.trim_start_matches('/').to_lowercase()))
+        }
+
+        async fn get_item_by_id(&self, id: &str, _: &Caches) -> Result<FileInfo, Error> {
+            match id.strip_prefix("id-") {
+                Some(name) => Ok(file(name)),
+                None => Err(Error::ItemNotFound { id: id.to_string() }),
+            }
+        }
+
+        async fn get_download(&self, id: &str, _: &Caches) -> Result<Download, Error> {
+            match id.starts_with("id-") {
+                true => Ok(Download::Url(format!("{}/{}", self.download_base, id))),
+                false => Err(Error::ItemNotFound { id: id.to_string() }),
+            }
+        }
+
+        async fn get_thumbnails(&self, id: &str, _: &Caches) -> Result<Thumbnails, Error> {
+            Err(Error::ItemNotFound { id: id.to_string() })
+        }
+
+        async fn search(&self, query: &str, _: &Caches) -> Result<Vec<FileInfo>, Error> {
+            Ok(["a.txt", "private/a.txt", "a.nfo"]
+                .into_iter()
+                .filter(|name| name.contains(query))
+                .map(file)
+                .collect())
+        }
+    }
+
+    fn caches() -> Arc<Caches> {
+        Arc::new(Caches::new(CACHE_DURATION))
+    }
+
+    fn test_app(extra: &str) -> Router {
+        app(content_router(
+            Arc::new(FakeBackend::default()),
+            caches(),
+            None,
+            &Setting::parse(extra),
+        ))
+    }
+
+    async fn get(uri: &str) -> Response {
+        test_app("")
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn json(response: Response) -> Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let response = get("/api/list").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let files = json(response).await;
+        assert_eq!(files["files"][1]["name"], "b.txt");
+
+        let response = get("/api/list/missing").await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_list_page() {
+        let page = json(get("/api/list?limit=1").await).await;
+        assert_eq!(page["files"].as_array().unwrap().len(), 1);
+        assert_eq!(page["files"][0]["name"], "a.txt");
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["next_offset"], 1);
+
+        let page = json(get("/api/list?offset=1&limit=5").await).await;
+        assert_eq!(page["files"][0]["name"], "b.txt");
+        assert!(page["next_offset"].is_null());
+
+        let page = json(get("/api/list?offset=9").await).await;
+        assert!(page["files"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_sort_and_filter() {
+        let page = json(get("/api/list?sort=name&order=desc").await).await;
+        assert_eq!(page["files"][0]["name"], "b.txt");
+        assert_eq!(page["files"][1]["name"], "a.txt");
+
+        let page = json(get("/api/list?q=B.&type=file").await).await;
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["files"][0]["name"], "b.txt");
+
+        let page = json(get("/api/list?type=video").await).await;
+        assert_eq!(page["total"], 0);
+
+        let response = get("/api/list?sort=color").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_info_and_download() {
+        let response = get("/api/info/a.txt").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json(response).await["file"]["id"], "id-a.txt");
+
+        let response = get("/api/download/id-a.txt").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/id-a.txt"
+        );
+
+        // Unknown ids are not found, rather than a failure of the server
+        for uri in ["/api/download/missing", "/api/thumb/small/id-a.txt"] {
+            let response = get(uri).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(json(response).await["error"], "Item not found");
+        }
+    }
+
+    /// A download server answering ranges and `If-None-Match` for "hello",
+    /// and 404 for anything else
+    async fn upstream() -> String {
+        async fn hello(request: Request<Body>) -> Response {
+            let headers = request.headers();
+            let etag = (header::ETAG, "\"v1\"");
+            if headers
+                .get(header::IF_NONE_MATCH)
+                .is_some_and(|tag| tag == "\"v1\"")
+            {
+                return (StatusCode::NOT_MODIFIED, [etag]).into_response();
+            }
+            match headers
+                .get(header::RANGE)
+                .map(|range| range.to_str().unwrap())
+            {
+                Some("bytes=0-1") => (
+                    StatusCode::PARTIAL_CONTENT,
+                    [etag, (header::CONTENT_RANGE, "bytes 0-1/5")],
+                    "he",
+                )
+                    .into_response(),
+                Some(_) => (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, "bytes */5")],
+                )
+                    .into_response(),
+                None => ([etag, (header::SET_COOKIE, "session=upstream")], "hello").into_response(),
+            }
+        }
+
+        // Lists the headers it was sent
+        async fn headers(request: Request<Body>) -> String {
+            let names: Vec<_> = request.headers().keys().map(|name| name.as_str()).collect();
+            names.join(",")
+        }
+
+        let upstream = Router::new()
+            .route("/id-hello", axum::routing::get(hello))
+            .route("/headers", axum::routing::get(headers))
+            .route(
+                "/id-broken",
+                axum::routing::get(|| async { StatusCode::FORBIDDEN }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_download() {
+        let base = upstream().await;
+        let caches = caches();
+        // Cached URLs refused by the upstream are replaced with fresh ones
+        caches.insert_download_url("id-hello".to_string(), format!("{}/expired", base));
+        let backend = FakeBackend {
+            download_base: base,
+        };
+        let mut config = Setting::parse("");
+        config.setting.use_proxy = true;
+        let app = app(content_router(Arc::new(backend), caches, None, &config));
+        let download = |name: &str, headers: &[(header::HeaderName, &str)]| {
+            let mut request = Request::get(format!("/api/download/id-{}", name));
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = download("hello", &[]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "\"v1\"");
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello");
+
+        let response = download("hello", &[(header::RANGE, "bytes=0-1")])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-1/5");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "he");
+
+        let response = download("hello", &[(header::RANGE, "bytes=9-")])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let response = download("hello", &[(header::IF_NONE_MATCH, "\"v1\"")])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = download("gone", &[]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = download("broken", &[]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_local_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"hello").unwrap();
+        let backend = storage::local::LocalStorage::new("", dir.path());
+        let id = backend.get_item("/a.txt", &caches()).await.unwrap().id;
+        let mut config = Setting::parse("[setting.proxy]\nmax_streams = 1");
+        config.setting.use_proxy = true;
+        let app = app(content_router(Arc::new(backend), caches(), None, &config));
+        let download = || {
+            let request = Request::get(format!("/api/download/{}", id));
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // Local files count against the same limits as the other downloads
+        let first = download().await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let response = download().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let body = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello");
+        let response = download().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_content_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FakeBackend {
+            download_base: upstream().await,
+        };
+        let mut config = Setting::parse(&format!(
+            "[setting.proxy]\ncache_dir = \"{}\"",
+            dir.path().display()
+        ));
+        config.setting.use_proxy = true;
+        let app = app(content_router(Arc::new(backend), caches(), None, &config));
+        let download = |range: Option<&str>| {
+            let mut request = Request::get("/api/download/id-hello");
+            if let Some(range) = range {
+                request = request.header(header::RANGE, range);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = download(None).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello");
+
+        // The upstream only answers the first two bytes, the rest comes from
+        // the cache
+        let response = download(Some("bytes=1-3")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 1-3/5");
+        assert_eq!(response.headers()[header::ETAG], "\"v1\"");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "ell");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_rules() {
+        let base = upstream().await;
+        let backend = FakeBackend {
+            download_base: base.clone(),
+        };
+        let mut config = Setting::parse(
+            r#"
+            [[proxy_rules]]
+            path = "/hello"
+            proxy = true
+
+            [[proxy_rules]]
+            min_size = 40
+            proxy = false
+            "#,
+        );
+        config.setting.use_proxy = true;
+        let caches = caches();
+        let url = format!("{}/id-hello", base);
+        let thumbnails = Thumbnails {
+            small: url.clone(),
+            medium: url.clone(),
+            large: url,
+        };
+        caches
+            .thumb_cache
+            .insert("id-a.txt".to_string(), thumbnails.into());
+        let app = app(content_router(Arc::new(backend), caches, None, &config));
+        let download = |name: &str| {
+            let request = Request::get(format!("/api/download/id-{}", name));
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = download("hello").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello");
+
+        let response = download("a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            format!("{}/id-a.txt", base)
+        );
+
+        // Thumbnails only follow `use_proxy`, whatever the size of the file
+        let request = Request::get("/api/thumb/small/id-a.txt");
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_thumb_headers() {
+        let caches = caches();
+        let url = format!("{}/headers", upstream().await);
+        let thumbnails = Thumbnails {
+            small: url.clone(),
+            medium: url.clone(),
+            large: url,
+        };
+        caches
+            .thumb_cache
+            .insert("id-a.txt".to_string(), thumbnails.into());
+        let mut config = Setting::parse("");
+        config.setting.use_proxy = true;
+        let app = app(content_router(
+            Arc::new(FakeBackend::default()),
+            caches,
+            None,
+            &config,
+        ));
+
+        let request = Request::get("/api/thumb/small/id-a.txt")
+            .header(header::COOKIE, "onelist_auth_0=token")
+            .header(header::AUTHORIZATION, "Bearer token")
+            .header(header::REFERER, "https://example.com")
+            .header(header::ACCEPT, "image/webp")
+            .header(header::RANGE, "bytes=0-1");
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Only what the thumbnail host needs is passed on
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let sent = String::from_utf8(body.to_vec()).unwrap();
+        assert!(sent.contains("range") && sent.contains("accept"));
+        assert!(!sent.contains("cookie"));
+        assert!(!sent.contains("authorization"));
+        assert!(!sent.contains("referer"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_login() {
+        let login = |token: Option<&str>, authorization: Option<&str>| {
+            let mut request = Request::post("/api/admin/login/music");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let extra = token.map_or(String::new(), |token| {
+                format!("admin_token = \"{}\"", token)
+            });
+            test_app(&extra).oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = login(None, Some("Bearer secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = login(Some("secret"), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = login(Some("secret"), Some("Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Authorized, but no drive is mounted as "music"
+        let response = login(Some("secret"), Some("Bearer secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json(response).await["error"], "Drive not found: music");
+    }
+
+    #[tokio::test]
+    async fn test_protected() {
+        let app = test_app(&format!(
+            r#"
+            [[protect]]
+            path = "/a.txt"
+            password = "{}"
+            "#,
+            hash_password("hunter2")
+        ));
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let unlock = |password: &str| {
+            Request::post("/api/auth")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({ "path": "/a.txt", "password": password }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let response = send(Request::get("/api/info/a.txt").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(json(response).await["protected"], "/a.txt");
+        let response = send(Request::get("/api/info/A.TXT").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send(Request::get("/d/A.TXT").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let download = || Request::get("/api/download/id-a.txt");
+        let response = send(download().body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(
+            Request::get("/api/download/id-b.txt")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let response = send(unlock("hunter3")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send(unlock("hunter2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+
+        let response = send(
+            download()
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn test_hidden() {
+        let app = test_app(
+            r#"
+            [hide]
+            glob = ["b.*"]
+            "#,
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/api/list").await.unwrap();
+        let files = json(response).await;
+        assert_eq!(files["files"].as_array().unwrap().len(), 1);
+
+        let response = get("/api/info/b.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get("/api/info/B.TXT").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get("/d/B.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get("/api/download/id-b.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get("/api/download/id-a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let app = test_app(
+            r#"
+            [[protect]]
+            path = "/private"
+            password = ""
+
+            [hide]
+            glob = ["*.nfo"]
+            "#,
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/api/search?q=a.").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let files = json(response).await;
+        assert_eq!(files["files"].as_array().unwrap().len(), 1);
+        assert_eq!(files["files"][0]["full_path"], "/a.txt");
+
+        let response = get("/api/search?q=%20").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_search_index() {
+        let index = Arc::new(SearchIndex::new("index.json.gz", String::new()));
+        let app = || {
+            app(content_router(
+                Arc::new(FakeBackend::default()),
+                caches(),
+                Some(index.clone()),
+                &Setting::parse(""),
+            ))
+        };
+        let search = || {
+            app().oneshot(
+                Request::get("/api/search?q=indexed")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        // Graph answers until the index is ready
+        let files = json(search().await.unwrap()).await;
+        assert!(files["files"].as_array().unwrap().is_empty());
+
+        index.update("/", vec![file("indexed.txt")]);
+        index.set_ready();
+        let files = json(search().await.unwrap()).await;
+        assert_eq!(files["files"][0]["full_path"], "/indexed.txt");
+    }
+
+    #[tokio::test]
+    async fn test_download_path() {
+        let response = get("/d/a.txt").await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/id-a.txt"
+        );
+
+        let response = get("/d/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"<a href="b.txt">b.txt</a>"#));
+    }
+
+    #[tokio::test]
+    async fn test_share() {
+        let app = test_app(r#"admin_token = "secret""#);
+        let share = |body: Value| {
+            app.clone().oneshot(
+                Request::post("/api/admin/share")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = share(json!({ "path": "/a.txt", "max_downloads": 1 }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let url = json(response).await["url"].as_str().unwrap().to_string();
+
+        let response = get(&url).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/id-a.txt"
+        );
+        let response = get(&url).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+        // Nor do ranges get around the limit
+        for range in ["bytes=0-", "bytes=10-"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get(&url)
+                        .header(header::RANGE, range)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::GONE);
+        }
+
+        let response = get(&format!("{}x", url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = share(json!({})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = share(json!({ "path": "/a.txt", "expires_in": u64::MAX }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,160 @@
+//! Whether a download is proxied or redirected, decided per request
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::{model::FileInfo, utils::config::ProxyRuleSetting};
+
+use super::auth::{is_under, normalize};
+
+#[derive(Debug)]
+pub struct ProxyPolicy {
+    rules: Vec<Rule>,
+    /// When no rule matches
+    default: bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    path: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    networks: Vec<IpNet>,
+    proxy: bool,
+}
+
+impl ProxyPolicy {
+    pub fn new(rules: &[ProxyRuleSetting], default: bool) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| Rule {
+                path: rule.path.as_deref().map(normalize),
+                min_size: rule.min_size,
+                max_size: rule.max_size,
+                networks: rule
+                    .network
+                    .iter()
+                    .map(|network| {
+                        parse_network(network)
+                            .unwrap_or_else(|| panic!("Invalid proxy rule network '{}'", network))
+                    })
+                    .collect(),
+                proxy: rule.proxy,
+            })
+            .collect();
+
+        Self { rules, default }
+    }
+
+    /// Whether to proxy what the rules aren't meant for, e.g. thumbnails
+    pub fn by_default(&self) -> bool {
+        self.default
+    }
+
+    /// Whether the rules look at the item, which then has to be looked up
+    pub fn needs_item(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.path.is_some() || rule.min_size.is_some() || rule.max_size.is_some())
+    }
+
+    /// Whether to proxy the download of `item` by `client`, a rule not
+    /// matching when what it looks at is unknown
+    pub fn proxy(&self, item: Option<&FileInfo>, client: Option<IpAddr>) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(item, client))
+            .map_or(self.default, |rule| rule.proxy)
+    }
+}
+
+impl Rule {
+    fn matches(&self, item: Option<&FileInfo>, client: Option<IpAddr>) -> bool {
+        let size = item.map(|item| item.size.max(0) as u64);
+        self.path
+            .as_ref()
+            .is_none_or(|path| item.is_some_and(|item| is_under(&item.full_path, path)))
+            && self
+                .min_size
+                .is_none_or(|min| size.is_some_and(|size| size >= min))
+            && self
+                .max_size
+                .is_none_or(|max| size.is_some_and(|size| size <= max))
+            && (self.networks.is_empty()
+                || client.is_some_and(|client| {
+                    self.networks
+                        .iter()
+                        .any(|network| network.contains(&client))
+                }))
+    }
+}
+
+/// A network in CIDR notation, or a single address
+fn parse_network(network: &str) -> Option<IpNet> {
+    network
+        .parse()
+        .ok()
+        .or_else(|| network.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::FileTypes;
+
+    use super::*;
+
+    fn file(full_path: &str, size: i64) -> FileInfo {
+        FileInfo {
+            id: "id".to_string(),
+            name: full_path.rsplit('/').next().unwrap().to_string(),
+            size,
+            last_modified_date_time: 0,
+            full_path: full_path.to_string(),
+            file_type: FileTypes::File,
+            etag: None,
+        }
+    }
+
+    fn rule(
+        path: Option<&str>,
+        min_size: Option<u64>,
+        network: &[&str],
+        proxy: bool,
+    ) -> ProxyRuleSetting {
+        ProxyRuleSetting {
+            path: path.map(str::to_string),
+            min_size,
+            max_size: None,
+            network: network.iter().map(|network| network.to_string()).collect(),
+            proxy,
+        }
+    }
+
+    #[test]
+    fn test_proxy() {
+        let policy = ProxyPolicy::new(
+            &[
+                rule(None, Some(2 << 30), &[], false),
+                rule(None, None, &["192.168.0.0/16", "::1"], true),
+                rule(Some("/Movies/"), None, &[], true),
+            ],
+            false,
+        );
+        assert!(policy.needs_item());
+
+        let lan = Some("192.168.1.2".parse().unwrap());
+        let wan = Some("8.8.8.8".parse().unwrap());
+        let small = file("/a.mkv", 1 << 20);
+        let large = file("/a.mkv", 3 << 30);
+        assert!(policy.proxy(Some(&small), lan));
+        assert!(!policy.proxy(Some(&small), wan));
+        assert!(!policy.proxy(Some(&large), lan));
+        assert!(policy.proxy(Some(&small), Some("::1".parse().unwrap())));
+        assert!(policy.proxy(Some(&file("/Movies/a.mkv", 1)), wan));
+        assert!(!policy.proxy(Some(&file("/Movies2/a.mkv", 1)), wan));
+        // Unknown sizes don't count as large
+        assert!(policy.proxy(None, lan));
+        assert!(!policy.proxy(None, None));
+    }
+}
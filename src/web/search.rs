@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json,
+};
+
+use serde::Deserialize;
+use serde_json::json;
+use snafu::{ResultExt, Snafu};
+
+use super::AppState;
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+async fn search(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SearchQuery>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(Error::EmptyQuery);
+    }
+
+    let results = match &state.index {
+        Some(index) if index.is_ready() => index.search(q),
+        _ => state
+            .backend
+            .search(q, &state.cache)
+            .await
+            .context(SearchSnafu)?,
+    };
+
+    // Results in hidden or locked folders are left out rather than refused
+    let files: Vec<_> = results
+        .into_iter()
+        .filter(|file| !state.hide.is_hidden(&file.full_path))
+        .filter(|file| state.protection.check(&file.full_path, &headers).is_ok())
+        .collect();
+
+    Ok(Json(json!({ "files": files })))
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+enum Error {
+    #[snafu(display("Missing search query"))]
+    EmptyQuery,
+
+    #[snafu(display("Failed to search: {}", source))]
+    Search { source: crate::storage::Error },
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        let status = match self {
+            Error::EmptyQuery => StatusCode::BAD_REQUEST,
+            Error::Search { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> axum::Router {
+    axum::Router::new()
+        .route("/search", get(search))
+        .with_state(state)
+}
@@ -0,0 +1,259 @@
+//! Signed links to a single file, for people without access to the listing
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde_json::json;
+use snafu::Snafu;
+
+use crate::utils::sign::Signer;
+
+use super::auth::now;
+
+/// What a share link points at
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Path(String),
+    Id(String),
+}
+
+impl Target {
+    fn encode(&self) -> String {
+        match self {
+            Target::Path(path) => format!("p:{}", path),
+            Target::Id(id) => format!("i:{}", id),
+        }
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        match s.split_once(':')? {
+            ("p", path) => Some(Target::Path(path.to_string())),
+            ("i", id) => Some(Target::Id(id.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// How a request uses a share link
+#[derive(Debug, Clone, Copy)]
+pub struct Use {
+    pub client: Option<IpAddr>,
+    /// Whether content is asked for, rather than only its headers
+    pub download: bool,
+    /// Whether the request goes on with a download from past its start, as
+    /// seeks and resumes do
+    pub resumed: bool,
+}
+
+/// The downloads of a link with a limit
+#[derive(Debug)]
+struct Downloads {
+    count: u32,
+    expires: u64,
+    /// Who the downloads were counted for, their resumes not counting again
+    clients: HashSet<IpAddr>,
+}
+
+/// A share link that checked out
+#[derive(Debug)]
+pub struct Redeemed {
+    pub target: Target,
+    /// The key of the download counted against the link's limit, to give
+    /// back with `refund` if it fails
+    pub counted: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ShareLinks {
+    signer: Signer,
+    /// Downloads of the links with a limit, by signature; kept in memory
+    /// only, so they start over on restart
+    downloads: Mutex<HashMap<String, Downloads>>,
+}
+
+impl ShareLinks {
+    pub fn new(signer: Signer) -> Self {
+        Self {
+            signer,
+            downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Mint the token of a link to `target` valid until `expires`
+    pub fn mint(&self, target: &Target, expires: u64, max_downloads: Option<u32>) -> String {
+        let max_downloads = max_downloads.map(|max| max.to_string()).unwrap_or_default();
+        let payload = format!("{}|{}|{}", expires, max_downloads, target.encode());
+
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            self.signer.sign(&signed(&payload))
+        )
+    }
+
+    /// Check `token`, refusing it once its downloads are used up, and count
+    /// the download unless it goes on with one counted for the same client
+    pub fn redeem(&self, token: &str, usage: Use) -> Result<Redeemed, Error> {
+        let (payload, signature) = token.split_once('.').ok_or(Error::Invalid)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| String::from_utf8(payload).ok())
+            .ok_or(Error::Invalid)?;
+        if !self.signer.verify(&signed(&payload), signature) {
+            return Err(Error::Invalid);
+        }
+
+        let mut parts = payload.splitn(3, '|');
+        let (Some(expires), Some(max_downloads), Some(target)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::Invalid);
+        };
+        let expires: u64 = expires.parse().map_err(|_| Error::Invalid)?;
+        let target = Target::decode(target).ok_or(Error::Invalid)?;
+
+        let now = now();
+        if expires <= now {
+            return Err(Error::Expired);
+        }
+
+        let mut counted = None;
+        if !max_downloads.is_empty() {
+            let max_downloads: u32 = max_downloads.parse().map_err(|_| Error::Invalid)?;
+            let mut downloads = self.downloads.lock().unwrap();
+            downloads.retain(|_, downloads| downloads.expires > now);
+
+            let downloads = downloads
+                .entry(signature.to_string())
+                .or_insert_with(|| Downloads {
+                    count: 0,
+                    expires,
+                    clients: HashSet::new(),
+                });
+            if downloads.count >= max_downloads {
+                return Err(Error::Exhausted);
+            }
+
+            let resumes = usage.resumed
+                && usage
+                    .client
+                    .is_some_and(|client| downloads.clients.contains(&client));
+            if usage.download && !resumes {
+                downloads.count += 1;
+                downloads.clients.extend(usage.client);
+                counted = Some(signature.to_string());
+            }
+        }
+
+        Ok(Redeemed { target, counted })
+    }
+
+    /// Give back a counted download that didn't go through
+    pub fn refund(&self, counted: &str) {
+        if let Some(downloads) = self.downloads.lock().unwrap().get_mut(counted) {
+            downloads.count = downloads.count.saturating_sub(1);
+        }
+    }
+}
+
+/// Keep share signatures apart from the other tokens signed with the key
+fn signed(payload: &str) -> String {
+    format!("share|{}", payload)
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("Invalid share link"))]
+    Invalid,
+
+    #[snafu(display("The share link has expired"))]
+    Expired,
+
+    #[snafu(display("The share link has reached its download limit"))]
+    Exhausted,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        let status = match self {
+            Error::Invalid => StatusCode::FORBIDDEN,
+            Error::Expired | Error::Exhausted => StatusCode::GONE,
+        };
+
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_links() {
+        let shares = ShareLinks::new(Signer::new(Some("secret")));
+        let target = Target::Path("/Movies/a.mkv".to_string());
+
+        let usage = |client: u8, resumed| Use {
+            client: Some(IpAddr::from([10, 0, 0, client])),
+            download: true,
+            resumed,
+        };
+
+        let token = shares.mint(&target, now() + 60, None);
+        assert_eq!(
+            shares.redeem(&token, usage(1, false)).unwrap().target,
+            target
+        );
+        assert!(shares
+            .redeem(&token, usage(1, false))
+            .unwrap()
+            .counted
+            .is_none());
+
+        let limited = shares.mint(&target, now() + 60, Some(2));
+        assert!(shares.redeem(&limited, usage(1, false)).is_ok());
+        // Seeking in a download under way doesn't count, unless it's someone
+        // else's
+        let resumed = shares.redeem(&limited, usage(1, true)).unwrap();
+        assert!(resumed.counted.is_none());
+        let counted = shares.redeem(&limited, usage(2, true)).unwrap();
+        let counted = counted.counted.unwrap();
+        // Used up, whatever is asked for
+        assert!(matches!(
+            shares.redeem(&limited, usage(1, true)),
+            Err(Error::Exhausted)
+        ));
+        assert!(matches!(
+            shares.redeem(&limited, usage(3, false)),
+            Err(Error::Exhausted)
+        ));
+        // A download that failed doesn't count
+        shares.refund(&counted);
+        assert!(shares.redeem(&limited, usage(3, false)).is_ok());
+
+        let expired = shares.mint(&target, now() - 1, None);
+        assert!(matches!(
+            shares.redeem(&expired, usage(1, false)),
+            Err(Error::Expired)
+        ));
+
+        // Pointing the payload at another file breaks the signature
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(format!("{}||p:/Movies/b.mkv", now() + 60)),
+            signature
+        );
+        assert!(matches!(
+            shares.redeem(&forged, usage(1, false)),
+            Err(Error::Invalid)
+        ));
+    }
+}
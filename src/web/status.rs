@@ -0,0 +1,30 @@
+use axum::{response::IntoResponse, routing::get, Json};
+use serde::Serialize;
+
+use crate::{onedrive::TokenStatus, DRIVES};
+
+#[derive(Debug, Serialize)]
+struct DriveStatus {
+    mount: String,
+    #[serde(flatten)]
+    status: TokenStatus,
+}
+
+async fn get_status() -> impl IntoResponse {
+    let mut drives: Vec<_> = DRIVES
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|(mount, drive)| DriveStatus {
+            mount: mount.clone(),
+            status: drive.load().status.clone(),
+        })
+        .collect();
+    drives.sort_by(|a, b| a.mount.cmp(&b.mount));
+
+    Json(serde_json::json!({ "drives": drives }))
+}
+
+pub fn router() -> axum::Router {
+    axum::Router::new().route("/status", get(get_status))
+}
@@ -0,0 +1,263 @@
+//! Bandwidth and concurrency limits on the downloads streamed through the
+//! server
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use futures_util::StreamExt;
+use serde_json::json;
+use snafu::Snafu;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::utils::config::ProxySetting;
+
+/// Seconds a client turned away is told to wait
+const RETRY_AFTER: u64 = 5;
+
+#[derive(Debug)]
+pub struct Throttle {
+    /// Free slots for proxied streams, unlimited when unset
+    streams: Option<Arc<Semaphore>>,
+    /// Shared by all proxied streams
+    global: Option<Arc<Bucket>>,
+    client_rate: Option<u64>,
+    /// The bucket of each client with a stream open
+    clients: Mutex<HashMap<IpAddr, Weak<Bucket>>>,
+    trust_forwarded: bool,
+}
+
+/// What a proxied stream holds on to until it's done
+pub struct Permit {
+    _stream: Option<OwnedSemaphorePermit>,
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Throttle {
+    pub fn new(setting: &ProxySetting) -> Self {
+        Self {
+            streams: setting.max_streams.map(|max| Arc::new(Semaphore::new(max))),
+            global: setting.rate.map(|rate| Arc::new(Bucket::new(rate))),
+            client_rate: setting.client_rate,
+            clients: Mutex::new(HashMap::new()),
+            trust_forwarded: setting.trust_forwarded,
+        }
+    }
+
+    /// Let `req` open a proxied stream, unless too many already are
+    pub fn admit(&self, req: &Request) -> Result<Permit, Error> {
+        let stream = match &self.streams {
+            Some(streams) => Some(
+                streams
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| Error::Busy)?,
+            ),
+            None => None,
+        };
+
+        let mut buckets: Vec<_> = self.global.iter().cloned().collect();
+        if let (Some(rate), Some(client)) = (self.client_rate, self.client_ip(req)) {
+            buckets.push(self.client_bucket(client, rate));
+        }
+
+        Ok(Permit {
+            _stream: stream,
+            buckets,
+        })
+    }
+
+    /// The address of the client behind `req`, taken from the reverse proxy
+    /// in front when trusted
+    pub fn client_ip(&self, req: &Request) -> Option<IpAddr> {
+        if self.trust_forwarded {
+            // The last address is the one the reverse proxy added, the others
+            // come from the client
+            let forwarded = req
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .next_back()
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+
+    fn client_bucket(&self, client: IpAddr, rate: u64) -> Arc<Bucket> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(bucket) = clients.get(&client).and_then(Weak::upgrade) {
+            return bucket;
+        }
+
+        clients.retain(|_, bucket| bucket.strong_count() > 0);
+        let bucket = Arc::new(Bucket::new(rate));
+        clients.insert(client, Arc::downgrade(&bucket));
+        bucket
+    }
+}
+
+impl Permit {
+    /// Pace `body` to the rates of the permit, which is released once the
+    /// body is done
+    pub fn limit(self, body: Body) -> Body {
+        let Permit { _stream, buckets } = self;
+        let stream = body.into_data_stream().then(move |chunk| {
+            // A closure only captures the fields it uses, so the slot is named
+            // here to live as long as the body
+            let _slot = &_stream;
+            let wait = match &chunk {
+                Ok(data) => buckets
+                    .iter()
+                    .map(|bucket| bucket.take(data.len()))
+                    .max()
+                    .unwrap_or_default(),
+                Err(_) => Duration::ZERO,
+            };
+
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                chunk
+            }
+        });
+
+        Body::from_stream(stream)
+    }
+}
+
+/// A token bucket holding a second worth of bytes
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Bytes that can be sent right away, negative when in debt
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to wait before
+    /// sending them
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.rate) - bytes as f64;
+        state.updated = now;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("Too many downloads in progress, try again later"))]
+    Busy,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, RETRY_AFTER.to_string())],
+            Json(json!({ "error": self.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(forwarded: Option<&str>) -> Request {
+        let mut request = Request::get("/");
+        if let Some(forwarded) = forwarded {
+            request = request.header("x-forwarded-for", forwarded);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))));
+        request
+    }
+
+    #[test]
+    fn test_admit() {
+        let throttle = Throttle::new(&ProxySetting {
+            max_streams: Some(1),
+            client_rate: Some(1000),
+            ..Default::default()
+        });
+
+        let permit = throttle.admit(&request(None)).unwrap();
+        assert_eq!(permit.buckets.len(), 1);
+        assert!(matches!(throttle.admit(&request(None)), Err(Error::Busy)));
+
+        drop(permit);
+        assert!(throttle.admit(&request(None)).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let mut setting = ProxySetting::default();
+        let throttle = Throttle::new(&setting);
+        let ip = |forwarded| throttle.client_ip(&request(forwarded)).unwrap();
+        assert_eq!(ip(Some("1.2.3.4")).to_string(), "10.0.0.1");
+
+        setting.trust_forwarded = true;
+        let throttle = Throttle::new(&setting);
+        let ip = |forwarded| throttle.client_ip(&request(forwarded)).unwrap();
+        assert_eq!(ip(Some("6.6.6.6, 1.2.3.4")).to_string(), "1.2.3.4");
+        assert_eq!(ip(None).to_string(), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_bucket() {
+        let bucket = Bucket::new(100);
+        assert_eq!(bucket.take(100), Duration::ZERO);
+
+        let wait = bucket.take(50);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+}
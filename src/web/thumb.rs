@@ -3,23 +3,23 @@ use std::sync::Arc;
 use axum::{
     body::Body,
     extract::{Path, Request, State},
-    http::{StatusCode, Uri},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Redirect, Response},
     routing::get,
     Json,
 };
-use onedrive_api::{option::ObjectOption, resource::DriveItemField, ItemId, ItemLocation};
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use snafu::{ResultExt as _, Snafu};
 
-use crate::{
-    model::{thumb::parse_thumb, Thumbnails},
-    DRIVE,
-};
+use crate::model::{Cached, Thumbnails};
 
-use super::AppState;
+use super::{
+    auth,
+    download::{not_found, FORWARDED_HEADERS},
+    AppState,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -29,14 +29,10 @@ pub enum Size {
     Large,
 }
 
-pub fn router(state: Arc<AppState>, use_proxy: bool) -> axum::Router {
-    let route = axum::Router::new();
-    let route = if use_proxy {
-        route.route("/{size}/{id}", get(proxy_thumb))
-    } else {
-        route.route("/{size}/{id}", get(thumb))
-    }
-    .with_state(state);
+pub fn router(state: Arc<AppState>) -> axum::Router {
+    let route = axum::Router::new()
+        .route("/{size}/{id}", get(thumb))
+        .with_state(state);
 
     axum::Router::new().nest("/thumb", route)
 }
@@ -44,8 +40,9 @@ pub fn router(state: Arc<AppState>, use_proxy: bool) -> axum::Router {
 async fn thumb(
     State(state): State<Arc<AppState>>,
     Path((size, id)): Path<(Size, String)>,
-) -> impl IntoResponse {
-    let thumb = match thumb_inner(state, &id).await {
+    req: Request,
+) -> Response {
+    let thumb = match thumb_inner(state.clone(), &id, req.headers()).await {
         Ok(thumb) => thumb,
         Err(e) => return e.into_response(),
     };
@@ -60,75 +57,63 @@ async fn thumb(
         return Response::builder().status(404).body(Body::empty()).unwrap();
     }
 
-    Redirect::to(url).into_response()
-}
+    // The proxy rules are about downloads, the size of a file says nothing
+    // of its thumbnails
+    if !state.policy.by_default() {
+        return Redirect::to(url).into_response();
+    }
 
-async fn proxy_thumb(
-    State(state): State<Arc<AppState>>,
-    Path((size, id)): Path<(Size, String)>,
-    mut req: Request,
-) -> impl IntoResponse {
-    let thumb = match thumb_inner(state.clone(), &id).await {
-        Ok(thumb) => thumb,
-        Err(e) => return e.into_response(),
-    };
+    proxy_thumb(&state, url, req).await
+}
 
-    let url = match size {
-        Size::Small => &thumb.small,
-        Size::Medium => &thumb.medium,
-        Size::Large => &thumb.large,
+async fn proxy_thumb(state: &AppState, url: &str, req: Request) -> Response {
+    let permit = match state.throttle.admit(&req) {
+        Ok(permit) => permit,
+        Err(e) => return Error::Throttled { source: e }.into_response(),
     };
-
-    if url.is_empty() {
-        return Response::builder().status(404).body(Body::empty()).unwrap();
-    }
-
     let client = &state.client;
 
-    req.headers_mut().remove("host");
-    req.headers_mut().remove("referer");
-    *req.uri_mut() = Uri::try_from(url).unwrap();
+    // A fresh request, so cookies and tokens meant for us stay here
+    let mut upstream = Request::get(url);
+    for name in FORWARDED_HEADERS.iter().chain([&header::ACCEPT]) {
+        for value in req.headers().get_all(name) {
+            upstream = upstream.header(name, value);
+        }
+    }
+    let upstream = match upstream.body(Body::empty()) {
+        Ok(upstream) => upstream,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
 
-    let ret = client.request(req).await;
+    let ret = client.request(upstream).await;
     match ret {
-        Ok(response) => response.into_response(),
+        Ok(response) => response.map(|body| permit.limit(Body::new(body))),
         Err(_) => StatusCode::BAD_REQUEST.into_response(),
     }
 }
 
-async fn thumb_inner(state: Arc<AppState>, id: &str) -> Result<Arc<Thumbnails>, Error> {
-    let drive = match DRIVE.get() {
-        Some(drive) => drive,
-        None => return Err(Error::StillStarting),
-    };
+async fn thumb_inner(
+    state: Arc<AppState>,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<Arc<Thumbnails>, Error> {
+    auth::check_id(&state, id, headers)
+        .await
+        .context(DeniedSnafu)?;
 
     let thumb_cache = &state.cache.thumb_cache;
     let cached_thumb = thumb_cache.get(&id.to_string());
     match cached_thumb {
-        Some(thumb) => Ok(thumb.clone()),
+        Some(thumb) => Ok(thumb.value().clone()),
         None => {
-            let thumb = drive
-                .load()
-                .drive
-                .get_item_with_option(
-                    ItemLocation::from_id(&ItemId(id.to_owned())),
-                    ObjectOption::default().expand(DriveItemField::thumbnails, None),
-                )
-                .await;
-
-            let thumb = match thumb {
-                Ok(Some(item)) => {
-                    if let Some(thumbnails) = &item.thumbnails {
-                        Arc::new(parse_thumb(thumbnails).context(ParseFailedSnafu)?)
-                    } else {
-                        return Err(Error::IdNotFound { id: id.to_string() });
-                    }
-                }
-                Ok(None) => return Err(Error::IdNotFound { id: id.to_string() }),
-                Err(e) => return Err(Error::GetItemInfo { source: e }),
-            };
-
-            thumb_cache.insert(id.to_string(), thumb.clone());
+            let thumb = state
+                .backend
+                .get_thumbnails(id, &state.cache)
+                .await
+                .context(GetThumbnailsSnafu)?;
+            let thumb = Arc::new(thumb);
+
+            thumb_cache.insert(id.to_string(), Cached::new(thumb.clone()));
 
             Ok(thumb)
         }
@@ -138,21 +123,25 @@ async fn thumb_inner(state: Arc<AppState>, id: &str) -> Result<Arc<Thumbnails>,
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 enum Error {
-    #[snafu(display("Server still in the process of starting up"))]
-    StillStarting,
+    #[snafu(display("Failed to get the thumbnails: {}", source))]
+    GetThumbnails { source: crate::storage::Error },
 
-    #[snafu(display("Location not found: {}", id))]
-    IdNotFound { id: String },
+    #[snafu(display("{}", source))]
+    Denied { source: super::auth::Error },
 
-    #[snafu(display("Failed to GetItemInfo: {}", source))]
-    GetItemInfo { source: onedrive_api::Error },
-
-    #[snafu(display("Failed to parse the thumb: {}", source))]
-    ParseFailed { source: crate::model::thumb::Error },
+    #[snafu(display("{}", source))]
+    Throttled { source: super::throttle::Error },
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        match self {
+            Error::Denied { source } => return source.into_response(),
+            Error::Throttled { source } => return source.into_response(),
+            Error::GetThumbnails { source } if source.is_not_found() => return not_found(),
+            _ => {}
+        }
+
         (
             axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({ "error": self.to_string() })),
@@ -0,0 +1,358 @@
+//! Invalidate the cached listings of OneDrive folders as they change
+//!
+//! Each drive polls the Graph `delta` endpoint, so folder contents can stay
+//! cached for long while new uploads still show up within a poll. The same
+//! changes mark the folders of the search index to list again.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use onedrive_api::resource::DriveItem;
+use tracing::{debug, info, warn};
+
+use crate::{
+    model::{Caches, FileTypes},
+    storage::{
+        index::{is_under, SearchIndex},
+        onedrive::OnedriveStorage,
+        Error, StorageBackend,
+    },
+};
+
+use super::backoff;
+
+/// How often the drives are asked for their changes
+const DELTA_INTERVAL: Duration = Duration::from_secs(30);
+/// Past this many changes at once, e.g. a large folder moved, flushing the
+/// listings beats looking up every changed folder
+const MAX_TARGETED: usize = 100;
+
+/// Where the items of a drive show up in the web caches
+#[derive(Debug)]
+pub struct Watched {
+    storage: OnedriveStorage,
+    mount: String,
+    /// The published path of the drive's root, empty when served at `/`
+    path_prefix: String,
+}
+
+impl Watched {
+    pub fn new(mount: &str, home_dir: &str, mounted: bool) -> Self {
+        let path_prefix = if mounted {
+            format!("/{}", mount)
+        } else {
+            String::new()
+        };
+
+        Self {
+            storage: OnedriveStorage::new(mount, home_dir),
+            mount: mount.to_string(),
+            path_prefix,
+        }
+    }
+
+    /// The published path of the folder at `path` inside the drive
+    fn list_key(&self, path: &str) -> String {
+        match format!("{}{}", self.path_prefix, path.trim_end_matches('/')) {
+            key if key.is_empty() => "/".to_string(),
+            key => key,
+        }
+    }
+}
+
+/// Poll the changes of `watched` for as long as the server runs
+pub fn spawn(watched: Watched, caches: Arc<Caches>, index: Option<Arc<SearchIndex>>) {
+    tokio::spawn(async move {
+        info!("Watching the changes of '{}'", watched.list_key("/"));
+
+        // Listings restored from a snapshot catch up from where the drive was
+        // last read, or can't be trusted
+        let mut delta_url = caches
+            .delta_urls
+            .lock()
+            .unwrap()
+            .get(&watched.mount)
+            .cloned();
+        if delta_url.is_none() {
+            flush(&watched, &caches);
+        }
+        let mut home_id = None;
+        let mut retries = 0;
+        loop {
+            let polled = poll(
+                &watched,
+                &caches,
+                index.as_deref(),
+                delta_url.as_deref(),
+                &mut home_id,
+            )
+            .await;
+            match polled {
+                Ok(next) => {
+                    caches
+                        .delta_urls
+                        .lock()
+                        .unwrap()
+                        .insert(watched.mount.clone(), next.clone());
+                    delta_url = Some(next);
+                    retries = 0;
+                    tokio::time::sleep(DELTA_INTERVAL).await;
+                }
+                Err(e) => {
+                    // Changes may have been missed, start over from now on
+                    warn!(
+                        "Failed to get the changes of '{}': {}",
+                        watched.list_key("/"),
+                        e
+                    );
+                    flush(&watched, &caches);
+                    if let Some(index) = &index {
+                        index.invalidate_tree(&watched.list_key("/"));
+                    }
+                    caches.delta_urls.lock().unwrap().remove(&watched.mount);
+                    delta_url = None;
+                    tokio::time::sleep(backoff(retries)).await;
+                    retries += 1;
+                }
+            }
+        }
+    });
+}
+
+/// Invalidate what changed since `delta_url`, returning where to continue
+async fn poll(
+    watched: &Watched,
+    caches: &Caches,
+    index: Option<&SearchIndex>,
+    delta_url: Option<&str>,
+    home_id: &mut Option<String>,
+) -> Result<String, Error> {
+    let home_id = match home_id {
+        Some(home_id) => home_id,
+        None => home_id.insert(watched.storage.home_id().await?),
+    };
+
+    let (changes, next) = watched.storage.changes(delta_url).await?;
+    if changes.is_empty() {
+        return Ok(next);
+    }
+    debug!(
+        "{} items of '{}' changed",
+        changes.len(),
+        watched.list_key("/")
+    );
+
+    if changes.len() > MAX_TARGETED {
+        flush(watched, caches);
+        if let Some(index) = index {
+            index.invalidate_tree(&watched.list_key("/"));
+        }
+    } else {
+        invalidate(watched, caches, index, &changes, home_id).await;
+    }
+    Ok(next)
+}
+
+/// Drop the cache entries of `changes` and of the folders holding them
+async fn invalidate(
+    watched: &Watched,
+    caches: &Caches,
+    index: Option<&SearchIndex>,
+    changes: &[DriveItem],
+    home_id: &str,
+) {
+    let ids: HashSet<_> = changes
+        .iter()
+        .filter_map(|item| item.id.as_ref())
+        .map(|id| id.0.clone())
+        .collect();
+
+    for id in &ids {
+        let key = watched.storage.cache_key(id);
+        caches.file_cache.invalidate(&key);
+        caches.thumb_cache.invalidate(&key);
+        caches.download_url_cache.invalidate(&key);
+        caches.published_cache.invalidate(&key);
+    }
+
+    // The listings holding a changed item, which also covers where moved and
+    // deleted items used to be, and the listings of changed folders
+    let prefixed: HashSet<_> = ids.iter().map(|id| watched.storage.cache_key(id)).collect();
+    let mut stale = HashSet::new();
+    for entry in caches.list_cache.iter() {
+        for child in entry.value().iter() {
+            if prefixed.contains(&child.id) {
+                stale.insert(normalize(entry.key()));
+                if matches!(child.file_type, FileTypes::Folder) {
+                    stale.insert(normalize(&child.full_path));
+                }
+            }
+        }
+    }
+
+    // Folders whose listing is cached while their parent's isn't, looked up
+    // for their current path unless their parent is known to be unpublished
+    let mut changed = Vec::new();
+    for item in changes {
+        let Some(id) = &item.id else {
+            continue;
+        };
+        if item.deleted.is_some() || (item.folder.is_none() && item.root.is_none()) {
+            continue;
+        }
+
+        if id.0 == home_id {
+            changed.push(watched.list_key("/"));
+        } else if !watched.storage.may_publish(item) {
+            continue;
+        } else if let Ok(folder) = watched.storage.get_item_by_id(&id.0, caches).await {
+            changed.push(watched.list_key(&folder.full_path));
+        }
+    }
+    stale.extend(changed.iter().map(|folder| normalize(folder)));
+
+    if let Some(index) = index {
+        for folder in index.folders_holding(&prefixed).iter().chain(&changed) {
+            index.invalidate(folder);
+        }
+    }
+
+    // Listings are cached under the path as asked for, in any case
+    invalidate_lists(caches, |key| stale.contains(&normalize(key)));
+}
+
+/// Drop every cached listing and item of the drive, leaving the other drives
+/// alone
+fn flush(watched: &Watched, caches: &Caches) {
+    let root = normalize(&watched.list_key("/"));
+    invalidate_lists(caches, |key| is_under(&normalize(key), &root));
+
+    let prefix = watched.storage.cache_key("");
+    let keys: Vec<_> = caches
+        .file_cache
+        .iter()
+        .filter(|entry| entry.key().starts_with(&prefix))
+        .map(|entry| entry.key().clone())
+        .collect();
+    for key in keys {
+        caches.file_cache.invalidate(&key);
+    }
+}
+
+fn invalidate_lists(caches: &Caches, stale: impl Fn(&str) -> bool) {
+    let keys: Vec<_> = caches
+        .list_cache
+        .iter()
+        .filter(|entry| stale(entry.key()))
+        .map(|entry| entry.key().clone())
+        .collect();
+    for key in keys {
+        caches.list_cache.invalidate(&key);
+    }
+}
+
+/// A folder path as OneDrive matches it, ignoring case and trailing slashes
+fn normalize(path: &str) -> String {
+    match path.trim_end_matches('/') {
+        "" => "/".to_string(),
+        path => path.to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::FileInfo;
+
+    use super::*;
+
+    fn folder(id: &str, full_path: &str) -> FileInfo {
+        FileInfo {
+            id: id.to_string(),
+            name: full_path.rsplit('/').next().unwrap().to_string(),
+            size: 0,
+            last_modified_date_time: 0,
+            full_path: full_path.to_string(),
+            file_type: FileTypes::Folder,
+            etag: None,
+        }
+    }
+
+    fn deleted(id: &str) -> DriveItem {
+        serde_json::from_value(serde_json::json!({ "id": id, "deleted": {} })).unwrap()
+    }
+
+    #[test]
+    fn test_list_key() {
+        assert_eq!(Watched::new("", "/", false).list_key("/"), "/");
+        assert_eq!(Watched::new("", "/", false).list_key("/a/"), "/a");
+        assert_eq!(Watched::new("music", "/", true).list_key("/"), "/music");
+        assert_eq!(Watched::new("music", "/", true).list_key("/a"), "/music/a");
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let watched = Watched::new("music", "/Music", true);
+        let caches = Caches::new(Duration::from_secs(60));
+        let list = |items: Vec<FileInfo>| items.into();
+        caches.list_cache.insert(
+            "/music".to_string(),
+            list(vec![folder("music:a", "/music/Albums")]),
+        );
+        caches.list_cache.insert(
+            "/music/Albums".to_string(),
+            list(vec![folder("music:b", "/music/Albums/Old")]),
+        );
+        caches
+            .list_cache
+            .insert("/music/Albums/Old/".to_string(), list(vec![]));
+        caches
+            .list_cache
+            .insert("/music/albums/old".to_string(), list(vec![]));
+        caches
+            .list_cache
+            .insert("/music/Other".to_string(), list(vec![]));
+        caches.insert_download_url("music:b".to_string(), "https://example.com".to_string());
+
+        let index = SearchIndex::new("index.json.gz", String::new());
+        index.update(
+            "/music/Albums",
+            vec![folder("music:b", "/music/Albums/Old")],
+        );
+
+        // Deleting the folder drops it from its parent and its own listing
+        invalidate(&watched, &caches, Some(&index), &[deleted("b")], "home").await;
+
+        let cached = |key: &str| caches.list_cache.get(&key.to_string()).is_some();
+        assert!(cached("/music"));
+        assert!(!cached("/music/Albums"));
+        assert!(!cached("/music/Albums/Old/"));
+        assert!(!cached("/music/albums/old"));
+        assert!(cached("/music/Other"));
+        assert!(caches.download_url("music:b").is_none());
+        assert_eq!(index.take_stale(), vec!["/music/Albums"]);
+    }
+
+    #[test]
+    fn test_flush() {
+        let watched = Watched::new("music", "/Music", true);
+        let caches = Caches::new(Duration::from_secs(60));
+        for key in ["/", "/Music/a", "/musicals", "/movies"] {
+            caches.list_cache.insert(key.to_string(), vec![].into());
+        }
+        for id in ["music:a", "movies:a"] {
+            caches
+                .file_cache
+                .insert(id.to_string(), folder(id, "/a").into());
+        }
+
+        // Only the listings and items of the failing drive go
+        flush(&watched, &caches);
+
+        let cached = |key: &str| caches.list_cache.get(&key.to_string()).is_some();
+        assert!(cached("/"));
+        assert!(!cached("/Music/a"));
+        assert!(cached("/musicals"));
+        assert!(cached("/movies"));
+        assert!(caches.file_cache.get(&"music:a".to_string()).is_none());
+        assert!(caches.file_cache.get(&"movies:a".to_string()).is_some());
+    }
+}
@@ -0,0 +1,226 @@
+//! Background crawl keeping the search index up to date
+
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    model::{Caches, FileTypes},
+    storage::{
+        index::{is_under, SearchIndex},
+        StorageBackend,
+    },
+};
+
+/// Pause between two listings, to stay clear of Graph's throttling
+const CRAWL_DELAY: Duration = Duration::from_millis(50);
+/// How long the crawl keeps what it parsed, it never reads it back
+const CRAWL_CACHE_TTL: Duration = Duration::from_secs(60);
+/// How often the folders the delta workers found changed are listed again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Load the saved index and crawl `backend` into it, then keep it up to date
+/// from the changes the delta workers report
+///
+/// Local folders report no changes, so with `recrawl` everything is crawled
+/// again that often.
+pub fn spawn(backend: Arc<dyn StorageBackend>, index: Arc<SearchIndex>, recrawl: Option<Duration>) {
+    tokio::spawn(async move {
+        if let Err(e) = index.load().await {
+            warn!("Failed to load the search index: {:?}", e);
+        }
+
+        // What changed while the server was down is only found by crawling,
+        // the loaded index answering searches in the meantime
+        let mut trees = vec!["/".to_string()];
+        let mut crawled = Instant::now();
+        loop {
+            if recrawl.is_some_and(|recrawl| crawled.elapsed() >= recrawl) {
+                trees = vec!["/".to_string()];
+            }
+            if trees.iter().any(|tree| tree == "/") {
+                crawled = Instant::now();
+            }
+
+            let stale = index.take_stale();
+            if !trees.is_empty() || !stale.is_empty() {
+                for tree in outermost(trees) {
+                    info!("Crawling '{}' into the search index", tree);
+                    crawl(backend.as_ref(), &index, &tree).await;
+                }
+                refresh(backend.as_ref(), &index, stale).await;
+
+                match index.save().await {
+                    Ok(_) => debug!("Search index saved"),
+                    Err(e) => error!("Failed to save the search index: {:?}", e),
+                }
+            }
+
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+            trees = index.take_trees();
+        }
+    });
+}
+
+/// The folders of `trees` not below another one, which crawls them along
+fn outermost(mut trees: Vec<String>) -> Vec<String> {
+    trees.sort_by_key(|tree| tree.len());
+    let mut outermost: Vec<String> = Vec::new();
+    for tree in trees {
+        if !outermost.iter().any(|outer| is_under(&tree, outer)) {
+            outermost.push(tree);
+        }
+    }
+    outermost
+}
+
+/// List every folder under `root`, replacing each folder in the index as
+/// soon as it's listed so searches keep working during the crawl
+pub async fn crawl(backend: &dyn StorageBackend, index: &SearchIndex, root: &str) {
+    // Apart from the web caches, so a crawl doesn't push out what visitors
+    // are browsing
+    let caches = Caches::new(CRAWL_CACHE_TTL);
+
+    let mut folders = vec![root.to_string()];
+    let mut listed = HashSet::new();
+    let mut failed = Vec::new();
+    while let Some(folder) = folders.pop() {
+        match backend.list_children(&folder, &caches).await {
+            Ok(children) => {
+                folders.extend(
+                    children
+                        .iter()
+                        .filter(|child| matches!(child.file_type, FileTypes::Folder))
+                        .map(|child| child.full_path.clone()),
+                );
+                index.update(&folder, children);
+                listed.insert(folder);
+            }
+            Err(e) => {
+                warn!("Failed to index '{}': {}", folder, e);
+                failed.push(folder);
+            }
+        }
+
+        tokio::time::sleep(CRAWL_DELAY).await;
+    }
+
+    // Forget the folders that are gone, keeping the ones that couldn't be
+    // listed this time around as they were
+    index.retain(|folder| {
+        !is_under(folder, root)
+            || listed.contains(folder)
+            || failed.iter().any(|failed| is_under(folder, failed))
+    });
+    if !listed.is_empty() {
+        index.set_ready();
+    }
+}
+
+/// List `folders` again, along with the subfolders that showed up in them
+pub async fn refresh(backend: &dyn StorageBackend, index: &SearchIndex, mut folders: Vec<String>) {
+    let caches = Caches::new(CRAWL_CACHE_TTL);
+
+    while let Some(folder) = folders.pop() {
+        match backend.list_children(&folder, &caches).await {
+            Ok(children) => {
+                let subfolders: HashSet<_> = children
+                    .iter()
+                    .filter(|child| matches!(child.file_type, FileTypes::Folder))
+                    .map(|child| child.full_path.clone())
+                    .collect();
+                for gone in index.subfolders(&folder) {
+                    if !subfolders.contains(&gone) {
+                        index.remove(&gone);
+                    }
+                }
+                folders.extend(
+                    subfolders
+                        .into_iter()
+                        .filter(|subfolder| !index.contains(subfolder)),
+                );
+                index.update(&folder, children);
+            }
+            // Moved or deleted, where it went shows up in its new parent
+            Err(e) if e.is_not_found() => index.remove(&folder),
+            Err(e) => {
+                warn!("Failed to index '{}': {}", folder, e);
+                index.invalidate(&folder);
+            }
+        }
+
+        tokio::time::sleep(CRAWL_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::local::LocalStorage;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_crawl() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("Shows/Season 1")).unwrap();
+        std::fs::write(dir.path().join("Shows/Season 1/e01.mkv"), b"").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"").unwrap();
+
+        let backend = LocalStorage::new("", dir.path());
+        let index = SearchIndex::new(dir.path().join("index.json.gz"), String::new());
+        crawl(&backend, &index, "/").await;
+
+        assert!(index.is_ready());
+        let found = index.search("e01");
+        assert_eq!(found[0].full_path, "/Shows/Season 1/e01.mkv");
+
+        // Removed folders leave the index on the next crawl
+        std::fs::remove_dir_all(dir.path().join("Shows")).unwrap();
+        crawl(&backend, &index, "/").await;
+        assert!(index.search("e01").is_empty());
+        assert_eq!(index.search("notes").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("Shows/Season 1")).unwrap();
+        std::fs::create_dir_all(dir.path().join("Movies")).unwrap();
+
+        let backend = LocalStorage::new("", dir.path());
+        let index = SearchIndex::new(dir.path().join("index.json.gz"), String::new());
+        crawl(&backend, &index, "/").await;
+
+        // New folders are listed along with the changed one
+        std::fs::create_dir_all(dir.path().join("Shows/Season 2")).unwrap();
+        std::fs::write(dir.path().join("Shows/Season 2/e01.mkv"), b"").unwrap();
+        std::fs::write(dir.path().join("Movies/m.mkv"), b"").unwrap();
+        refresh(&backend, &index, vec!["/Shows".to_string()]).await;
+        assert_eq!(index.search("e01")[0].full_path, "/Shows/Season 2/e01.mkv");
+        // Folders that weren't reported changed stay as they were
+        assert!(index.search("m.mkv").is_empty());
+
+        // Removed subfolders leave the index with everything below them
+        std::fs::remove_dir_all(dir.path().join("Shows/Season 2")).unwrap();
+        refresh(&backend, &index, vec!["/Shows".to_string()]).await;
+        assert!(index.search("e01").is_empty());
+        assert!(!index.contains("/Shows/Season 2"));
+
+        // So do folders that are gone themselves
+        std::fs::remove_dir_all(dir.path().join("Shows")).unwrap();
+        refresh(&backend, &index, vec!["/Shows".to_string()]).await;
+        assert!(!index.contains("/Shows"));
+        assert!(!index.contains("/Shows/Season 1"));
+    }
+
+    #[test]
+    fn test_outermost() {
+        let trees = |trees: &[&str]| trees.iter().map(|tree| tree.to_string()).collect();
+        assert_eq!(outermost(trees(&["/a/b", "/a", "/ab"])), vec!["/a", "/ab"]);
+        assert_eq!(outermost(trees(&["/a", "/"])), vec!["/"]);
+    }
+}
@@ -1,43 +1,128 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
 use tracing::{debug, error, info};
 
-use crate::DRIVE;
+use crate::{
+    onedrive::{Error, Onedrive, TokenStatus},
+    DRIVES, TOKEN_STORE,
+};
+
+pub mod delta;
+pub mod index;
+pub mod snapshot;
+
+/// The longest wait between two attempts to refresh a degraded drive
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 10);
+/// How long an interactive login may wait for the user
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60 * 15);
 
 pub fn worker() {
-    // Automatically refresh the token when it expires
-    tokio::spawn(async {
-        info!("Starting the worker");
-        auto_refresh().await;
-    });
+    // Automatically refresh the tokens when they expire
+    let Some(drives) = DRIVES.get() else {
+        return;
+    };
+    for mount in drives.keys() {
+        let mount = mount.clone();
+        tokio::spawn(async move {
+            info!("Starting the worker for drive '{}'", mount);
+            auto_refresh(&mount).await;
+        });
+    }
 }
 
-async fn auto_refresh() {
+async fn auto_refresh(mount: &str) {
     loop {
-        if let Some(drive) = DRIVE.get() {
-            let drive_load = drive.load();
-            let expires_at = drive_load.token.expires_at;
-            let now = std::time::Instant::now();
-            let duration = expires_at.duration_since(now) - std::time::Duration::from_secs(60);
-            debug!("Token will be refreshed in {:?}", duration);
-            tokio::time::sleep(duration).await;
-
-            debug!("Refreshing the token");
-
-            let drive_load = drive.load_full();
-            let ret = drive_load.refresh().await;
-            match ret {
-                Ok(d) => {
-                    DRIVE.get().unwrap().store(Arc::new(d));
-                    debug!("Token refreshed");
-                }
-                Err(e) => {
-                    error!("Failed to refresh the token: {:?}", e);
-                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                }
+        let Some(drive) = DRIVES.get().and_then(|drives| drives.get(mount)) else {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        };
+
+        let current = drive.load_full();
+        let duration = match &current.status {
+            TokenStatus::Ok => current
+                .token
+                .expires_at
+                .saturating_duration_since(std::time::Instant::now())
+                .saturating_sub(Duration::from_secs(60)),
+            TokenStatus::Degraded { retries, .. } => backoff(*retries),
+            // Wait for an admin to log in again
+            TokenStatus::ReauthRequired { .. } => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                continue;
+            }
+        };
+        debug!(
+            "Token of drive '{}' will be refreshed in {:?}",
+            mount, duration
+        );
+        tokio::time::sleep(duration).await;
+
+        debug!("Refreshing the token");
+        let refreshed = match current.refresh().await {
+            Ok(d) => {
+                debug!("Token refreshed");
+                d
+            }
+            Err(e) => {
+                error!("Failed to refresh the token of drive '{}': {:?}", mount, e);
+                current.with_error(&e)
+            }
+        };
+        // Keep a login that finished in the meantime
+        let previous = drive.compare_and_swap(&current, Arc::new(refreshed));
+        if Arc::ptr_eq(&previous, &current) {
+            persist_tokens().await;
+        }
+    }
+}
+
+/// Write the refresh tokens of all drives to the token store
+pub async fn persist_tokens() {
+    let (Some(store), Some(drives)) = (TOKEN_STORE.get(), DRIVES.get()) else {
+        return;
+    };
+    match store.save(drives).await {
+        Ok(_) => debug!("Tokens saved"),
+        Err(e) => error!("Failed to save the tokens: {:?}", e),
+    }
+}
+
+fn backoff(retries: u32) -> Duration {
+    Duration::from_secs(5)
+        .saturating_mul(2u32.saturating_pow(retries))
+        .min(MAX_BACKOFF)
+}
+
+/// Start an interactive login for `drive`, returning the instructions for the
+/// user; the drive is swapped in once they have signed in
+pub async fn login(mount: &str, drive: &'static ArcSwap<Onedrive>) -> Result<String, Error> {
+    let (prompt, signed_in) = drive.load().start_login().await?;
+
+    let mount = mount.to_string();
+    tokio::spawn(async move {
+        match tokio::time::timeout(LOGIN_TIMEOUT, signed_in).await {
+            Ok(Ok(onedrive)) => {
+                drive.store(Arc::new(onedrive));
+                info!("Drive '{}' logged in", mount);
+                persist_tokens().await;
             }
-        } else {
-            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            Ok(Err(e)) => error!("Failed to login drive '{}': {:?}", mount, e),
+            Err(_) => error!("Login of drive '{}' timed out", mount),
         }
+    });
+
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_secs(5));
+        assert_eq!(backoff(3), Duration::from_secs(40));
+        assert_eq!(backoff(100), MAX_BACKOFF);
     }
 }
@@ -0,0 +1,21 @@
+//! Periodic snapshots of the caches, in case the server doesn't get to
+//! save them on shutdown
+
+use std::{sync::Arc, time::Duration};
+
+use tracing::{debug, error};
+
+use crate::model::{snapshot::CacheSnapshot, Caches};
+
+/// Save `caches` to `snapshot` every `interval`
+pub fn spawn(snapshot: Arc<CacheSnapshot>, caches: Arc<Caches>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match snapshot.save(&caches).await {
+                Ok(_) => debug!("Cache snapshot saved"),
+                Err(e) => error!("Failed to save the cache snapshot: {:?}", e),
+            }
+        }
+    });
+}
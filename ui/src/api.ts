@@ -0,0 +1,25 @@
+// fetch an API url, asking for the password when it is in a protected folder
+export async function fetchWithPassword(url: string): Promise<Response> {
+  const res = await fetch(url)
+  if (res.status !== 401) {
+    return res
+  }
+
+  const json = await res.clone().json()
+  if (!json.protected) {
+    return res
+  }
+
+  const password = window.prompt(`Password for ${json.protected}`)
+  if (password === null) {
+    return res
+  }
+
+  // the server sets a cookie unlocking the folder
+  await fetch('/api/auth', {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify({ path: json.protected, password }),
+  })
+  return fetchWithPassword(url)
+}